@@ -0,0 +1,41 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use rbvm::assembler::Assembler;
+use rbvm::bench::count_instructions;
+use rbvm::vm::Vm;
+
+/// Programs in `benches/workloads`, also runnable with `rbvm bench`.
+const WORKLOADS: [(&str, &str); 3] = [
+    ("counting", include_str!("workloads/counting.rbvm")),
+    ("arithmetic", include_str!("workloads/arithmetic.rbvm")),
+    ("branches", include_str!("workloads/branches.rbvm")),
+];
+
+/// Runs every workload with each dispatch mode. Throughput is reported in
+/// executed instructions.
+fn workloads(c: &mut Criterion) {
+    for (name, source) in WORKLOADS {
+        let bytecode = Assembler::default().assemble(source).unwrap();
+        let mut group = c.benchmark_group(name);
+        for mode in ["decoded", "predecoded", "fused"] {
+            let mut vm = Vm::default();
+            vm.load_bytecode(&bytecode).unwrap();
+            match mode {
+                "predecoded" => vm.enable_predecoding(),
+                "fused" => vm.enable_fusion(),
+                _ => {}
+            }
+            let instructions = count_instructions(&vm).unwrap();
+            group.throughput(Throughput::Elements(instructions));
+            group.bench_function(mode, |b| {
+                b.iter(|| {
+                    vm.reset();
+                    vm.run().unwrap()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, workloads);
+criterion_main!(benches);
@@ -0,0 +1,267 @@
+use super::{Assembler, Error, Instruction, MaybeToken, Program, Token};
+use crate::opcodes::Opcode;
+use crate::vm::Config;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Register(pub u8);
+
+pub const R0: Register = Register(0);
+pub const R1: Register = Register(1);
+pub const R2: Register = Register(2);
+pub const R3: Register = Register(3);
+pub const R4: Register = Register(4);
+pub const R5: Register = Register(5);
+pub const R6: Register = Register(6);
+pub const R7: Register = Register(7);
+
+/// Immediate operand, either a number or the address of a label.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Immediate {
+    Integer(i32),
+    Label(String),
+}
+
+impl From<i32> for Immediate {
+    fn from(value: i32) -> Self {
+        Immediate::Integer(value)
+    }
+}
+
+impl From<&str> for Immediate {
+    fn from(name: &str) -> Self {
+        Immediate::Label(name.to_string())
+    }
+}
+
+/// Builds a [`Program`] without going through assembly text. Labels and
+/// operands follow the rules of [`Assembler::assemble`], so both produce the
+/// same program and bytecode:
+/// ```
+/// use rbvm::assembler::emitter::{Emitter, R0, R1, R2};
+///
+/// // load $0 #3, load $1 @loop, loop: dec $0, eq $0 $2, jne $1, halt
+/// let bytecode = Emitter::new()
+///     .load(R0, 3)
+///     .load(R1, "loop")
+///     .label("loop")
+///     .dec(R0)
+///     .eq(R0, R2)
+///     .jne(R1)
+///     .halt()
+///     .assemble()?;
+/// # Ok::<(), rbvm::assembler::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct Emitter {
+    program: Program,
+    config: Config,
+    /// The first error found while emitting, reported by `finish`.
+    error: Option<Error>,
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Emitter::default()
+    }
+
+    /// Creates an emitter targeting a VM with the given configuration.
+    pub fn with_config(config: Config) -> Self {
+        Emitter {
+            config,
+            ..Emitter::default()
+        }
+    }
+
+    /// Declares a label pointing at the next emitted instruction.
+    pub fn label(mut self, name: &str) -> Self {
+        let target = self.program.instrs.len();
+        let labels = &mut self.program.labels;
+        if labels.insert(name.to_string(), target).is_some() {
+            self.error.get_or_insert(Error::DuplicateLabel);
+        }
+        self
+    }
+
+    pub fn halt(self) -> Self {
+        self.emit(Opcode::HALT, (None, None, None))
+    }
+
+    /// Emits `halt #code`.
+    pub fn halt_with(self, code: i32) -> Self {
+        let code = immediate(code.into());
+        self.emit(Opcode::HALT, (code, None, None))
+    }
+
+    pub fn load(self, register: Register, value: impl Into<Immediate>) -> Self {
+        let value = immediate(value.into());
+        self.emit(Opcode::LOAD, (reg(register), value, None))
+    }
+
+    pub fn add(self, lhs: Register, rhs: Register, dest: Register) -> Self {
+        self.emit(Opcode::ADD, (reg(lhs), reg(rhs), reg(dest)))
+    }
+
+    pub fn sub(self, lhs: Register, rhs: Register, dest: Register) -> Self {
+        self.emit(Opcode::SUB, (reg(lhs), reg(rhs), reg(dest)))
+    }
+
+    pub fn mul(self, lhs: Register, rhs: Register, dest: Register) -> Self {
+        self.emit(Opcode::MUL, (reg(lhs), reg(rhs), reg(dest)))
+    }
+
+    pub fn div(self, lhs: Register, rhs: Register, dest: Register) -> Self {
+        self.emit(Opcode::DIV, (reg(lhs), reg(rhs), reg(dest)))
+    }
+
+    pub fn jump(self, target: Register) -> Self {
+        self.emit(Opcode::JUMP, (reg(target), None, None))
+    }
+
+    pub fn jf(self, offset: Register) -> Self {
+        self.emit(Opcode::JF, (reg(offset), None, None))
+    }
+
+    pub fn jb(self, offset: Register) -> Self {
+        self.emit(Opcode::JB, (reg(offset), None, None))
+    }
+
+    pub fn eq(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::EQ, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn ne(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::NE, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn gt(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::GT, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn lt(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::LT, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn gte(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::GTE, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn lte(self, lhs: Register, rhs: Register) -> Self {
+        self.emit(Opcode::LTE, (reg(lhs), reg(rhs), None))
+    }
+
+    pub fn je(self, target: Register) -> Self {
+        self.emit(Opcode::JE, (reg(target), None, None))
+    }
+
+    pub fn jne(self, target: Register) -> Self {
+        self.emit(Opcode::JNE, (reg(target), None, None))
+    }
+
+    pub fn inc(self, register: Register) -> Self {
+        self.emit(Opcode::INC, (reg(register), None, None))
+    }
+
+    pub fn dec(self, register: Register) -> Self {
+        self.emit(Opcode::DEC, (reg(register), None, None))
+    }
+
+    pub fn syscall(self, number: i32) -> Self {
+        let number = immediate(number.into());
+        self.emit(Opcode::SYSCALL, (number, None, None))
+    }
+
+    /// Checks registers and labels and returns the emitted program.
+    pub fn finish(self) -> Result<Program, Error> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        self.program.validate(&self.config)?;
+        self.program.to_bytes()?;
+        Ok(self.program)
+    }
+
+    /// Returns bytecode prefixed with a header, like [`Assembler::assemble`].
+    pub fn assemble(self) -> Result<Vec<u8>, Error> {
+        let config = self.config;
+        Assembler::new(config).assemble_program(self.finish()?)
+    }
+
+    fn emit(
+        mut self,
+        opcode: Opcode,
+        operands: (MaybeToken, MaybeToken, MaybeToken),
+    ) -> Self {
+        let opcode = Some(Token::Op { code: opcode });
+        self.program.instrs.push(Instruction::new(opcode, operands));
+        self
+    }
+}
+
+fn reg(register: Register) -> MaybeToken {
+    Some(Token::Register { index: register.0 })
+}
+
+fn immediate(value: Immediate) -> MaybeToken {
+    match value {
+        Immediate::Integer(value) => Some(Token::Integer { value }),
+        Immediate::Label(name) => Some(Token::Label { name }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emitter_matches_assembler() {
+        let source_code = "load $0 #3
+load $1 @loop
+loop: dec $0
+eq $0 $2
+add $0 $3 $4
+jne $1
+syscall #1
+halt #7
+";
+        let mut assembler = Assembler::default();
+        let assembled = assembler.assemble(source_code).unwrap();
+
+        let emitter = || {
+            Emitter::new()
+                .load(R0, 3)
+                .load(R1, "loop")
+                .label("loop")
+                .dec(R0)
+                .eq(R0, R2)
+                .add(R0, R3, R4)
+                .jne(R1)
+                .syscall(1)
+                .halt_with(7)
+        };
+        assert_eq!(&emitter().finish().unwrap(), assembler.program());
+        assert_eq!(emitter().assemble().unwrap(), assembled);
+    }
+
+    #[test]
+    fn test_emitter_errors() {
+        let result = Emitter::new().label("a").halt().label("a").finish();
+        assert!(matches!(result, Err(Error::DuplicateLabel)));
+        let result = Emitter::new().load(R0, "nowhere").finish();
+        assert!(matches!(result, Err(Error::UnknownLabel)));
+        let result = Emitter::new().inc(Register(8)).finish();
+        assert!(matches!(result, Err(Error::RegisterOutOfRange)));
+        let result = Emitter::new().syscall(-1).finish();
+        assert!(matches!(result, Err(Error::ImmediateOutOfRange)));
+        let result = Emitter::new().halt_with(-1).finish();
+        assert!(matches!(result, Err(Error::ImmediateOutOfRange)));
+        let result = Emitter::new().load(R0, 70000).finish();
+        assert!(matches!(result, Err(Error::ImmediateOutOfRange)));
+
+        let config = Config {
+            registers: 16,
+            ..Config::default()
+        };
+        let result = Emitter::with_config(config).inc(Register(8)).finish();
+        assert!(result.is_ok());
+    }
+}
@@ -1,55 +1,239 @@
-use program_parser::program_parser;
+use std::collections::BTreeMap;
+use std::fmt;
 
+use program_parser::{label_decl_parser, line_parser};
+
+use crate::debug_info::{DebugInfo, Entry};
+use crate::header::Header;
+use crate::instruction::DecodedInstruction::{self, self as I};
 use crate::opcodes::Opcode;
+use crate::vm::Config;
 
+pub mod emitter;
 pub mod instruction_parsers;
 pub mod opcode_parser;
 pub mod operand_parsers;
+pub mod optimizer;
 pub mod program_parser;
 
+pub use crate::instruction::INSTRUCTION_LEN;
+
 #[derive(Debug)]
 pub enum Error {
     ParseError,
     NotOpcode,
     OpcodeOperand,
+    RegisterOutOfRange,
+    DuplicateLabel,
+    UnknownLabel,
+    InvalidOperands,
+    ImmediateOutOfRange,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ParseError => write!(f, "source line could not be parsed"),
+            Error::NotOpcode => {
+                write!(f, "instruction doesn't start with an opcode")
+            }
+            Error::OpcodeOperand => write!(f, "opcode used as an operand"),
+            Error::RegisterOutOfRange => {
+                write!(f, "register is out of range for the VM")
+            }
+            Error::DuplicateLabel => write!(f, "label is declared twice"),
+            Error::UnknownLabel => write!(f, "label is not declared"),
+            Error::InvalidOperands => {
+                write!(f, "operands don't match the opcode")
+            }
+            Error::ImmediateOutOfRange => {
+                write!(f, "immediate doesn't fit in 16 bits")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug, Default)]
 pub struct Assembler {
     program: Program,
     bytecode: Vec<u8>,
+    config: Config,
+    debug_info: DebugInfo,
+    embed_debug_info: bool,
+    optimize: bool,
 }
 impl Assembler {
+    /// Creates an assembler targeting a VM with the given configuration.
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Names the source file recorded in the debug info.
+    pub fn set_file(&mut self, file: impl Into<String>) {
+        self.debug_info.file = file.into();
+    }
+
+    /// Appends the debug info to the bytecode produced by
+    /// [`Assembler::assemble`].
+    pub fn set_embed_debug_info(&mut self, embed: bool) {
+        self.embed_debug_info = embed;
+    }
+
+    /// Runs [`optimizer::optimize`] on programs before emitting them.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// Assembles the source code into bytecode prefixed with a [`Header`].
     pub fn assemble(&mut self, source_code: &str) -> Result<Vec<u8>, Error> {
-        match program_parser(source_code) {
-            Ok((_, program)) => Ok(self.emit_bytecode(program)?),
-            Err(_err) => Err(Error::ParseError),
+        let mut program = Program::default();
+        let mut entries = vec![];
+        let mut pending_label = None;
+        for (index, line) in source_code.lines().enumerate() {
+            let (label, instr) = match line_parser(line) {
+                Ok((_, parsed)) => parsed,
+                Err(_err) => return Err(Error::ParseError),
+            };
+            if let Some(label) = label {
+                let target = program.instrs.len();
+                if program.labels.insert(label.to_string(), target).is_some() {
+                    return Err(Error::DuplicateLabel);
+                }
+                pending_label.get_or_insert(label.to_string());
+            }
+            if let Some(instr) = instr {
+                entries.push(Entry {
+                    offset: program.instrs.len() * INSTRUCTION_LEN,
+                    line: index + 1,
+                    column: instruction_column(line),
+                    label: pending_label.take(),
+                });
+                program.instrs.push(instr);
+            }
         }
+        self.debug_info.entries = entries;
+        self.emit_bytecode(program)
+    }
+
+    /// Assembles a program built without source code, e.g. by an
+    /// [`emitter::Emitter`]. The debug info is left empty.
+    pub fn assemble_program(
+        &mut self,
+        program: Program,
+    ) -> Result<Vec<u8>, Error> {
+        self.debug_info.entries.clear();
+        self.emit_bytecode(program)
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
     }
 
-    fn emit_bytecode(&mut self, program: Program) -> Result<Vec<u8>, Error> {
-        self.bytecode = program.to_bytes()?;
+    /// Returns the 1-based source line of the instruction at the given code
+    /// offset.
+    pub fn source_line(&self, offset: usize) -> Option<usize> {
+        self.debug_info.line(offset)
+    }
+
+    fn emit_bytecode(
+        &mut self,
+        mut program: Program,
+    ) -> Result<Vec<u8>, Error> {
+        program.validate(&self.config)?;
+        if self.optimize {
+            let origins = optimizer::optimize(&mut program);
+            self.remap_debug_info(&origins);
+        }
+        let header = Header {
+            debug_info: self.embed_debug_info,
+            ..Header::new(self.config.width)
+        };
+        self.bytecode = header.to_bytes();
+        self.bytecode.append(&mut program.to_bytes()?);
+        if self.embed_debug_info {
+            let section = self.debug_info.to_bytes();
+            self.bytecode.extend(&section);
+            self.bytecode.extend((section.len() as u32).to_be_bytes());
+        }
         self.program = program;
         Ok(self.bytecode.clone())
     }
+
+    /// Moves debug entries along with their optimised instructions. The label
+    /// of a removed instruction passes to the next remaining one.
+    fn remap_debug_info(&mut self, origins: &[usize]) {
+        let new_offsets: BTreeMap<usize, usize> = origins
+            .iter()
+            .enumerate()
+            .map(|(new, old)| (old * INSTRUCTION_LEN, new * INSTRUCTION_LEN))
+            .collect();
+        let mut label = None;
+        let entries = std::mem::take(&mut self.debug_info.entries);
+        for mut entry in entries {
+            match new_offsets.get(&entry.offset) {
+                Some(offset) => {
+                    entry.offset = *offset;
+                    entry.label = entry.label.or(label.take());
+                    self.debug_info.entries.push(entry);
+                }
+                None => {
+                    label = label.or(entry.label);
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Default)]
+/// Returns the 1-based column of the instruction on a source line.
+fn instruction_column(line: &str) -> usize {
+    let code = line.trim_start();
+    let code = match label_decl_parser(code) {
+        Ok((rest, _label)) => rest,
+        Err(_err) => code,
+    };
+    line.len() - code.len() + 1
+}
+
+#[derive(Debug, PartialEq, Default, Clone)]
 pub struct Program {
     pub instrs: Vec<Instruction>,
+    /// Label names mapped to the index of the instruction they precede.
+    pub labels: BTreeMap<String, usize>,
 }
 impl Program {
     pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut bytecode = vec![];
         for instr in &self.instrs {
-            let mut bytes = instr.to_bytes()?;
+            let mut bytes = instr.resolve_labels(&self.labels)?.to_bytes()?;
             bytecode.append(&mut bytes);
         }
         Ok(bytecode)
     }
+
+    /// Returns the code offset a label points at.
+    pub fn label_offset(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).map(|index| index * INSTRUCTION_LEN)
+    }
+
+    /// Checks that every register operand exists on the target VM.
+    pub fn validate(&self, config: &Config) -> Result<(), Error> {
+        for instr in &self.instrs {
+            instr.validate(config)?;
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Instruction {
     opcode: MaybeToken,
     operands: (MaybeToken, MaybeToken, MaybeToken),
@@ -63,58 +247,126 @@ impl Instruction {
     }
 
     pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
-        let mut parsed = vec![];
-        if let Some(Token::Op { code }) = &self.opcode {
-            parsed.push(*code as u8);
-        } else {
+        Ok(self.decoded()?.encode().to_vec())
+    }
+
+    /// Converts the instruction into its typed form. Labels must have been
+    /// resolved first.
+    pub fn decoded(&self) -> Result<DecodedInstruction, Error> {
+        let Some(Token::Op { code }) = &self.opcode else {
             return Err(Error::NotOpcode);
         };
-
-        for operand in
-            [&self.operands.0, &self.operands.1, &self.operands.2].iter()
-        {
-            if let Some(token) = *operand {
-                Instruction::extract_operand(token, &mut parsed)?
-            }
-        }
-        while parsed.len() < 4 {
-            parsed.push(0);
+        let (x, y, z) = &self.operands;
+        let (a, b, c) = (reg(x), reg(y), reg(z));
+        #[rustfmt::skip]
+        let (decoded, arity) = match code {
+            Opcode::PAD     => (I::Pad, 0),
+            Opcode::HALT if x.is_none() => (I::Halt { code: 0 }, 0),
+            Opcode::HALT    => (I::Halt { code: imm(x)? }, 1),
+            Opcode::LOAD    => (I::Load { reg: a?, imm: imm(y)? }, 2),
+            Opcode::ADD     => (I::Add { a: a?, b: b?, dst: c? }, 3),
+            Opcode::SUB     => (I::Sub { a: a?, b: b?, dst: c? }, 3),
+            Opcode::MUL     => (I::Mul { a: a?, b: b?, dst: c? }, 3),
+            Opcode::DIV     => (I::Div { a: a?, b: b?, dst: c? }, 3),
+            Opcode::JUMP    => (I::Jump { target: a? }, 1),
+            Opcode::JF      => (I::Jf { offset: a? }, 1),
+            Opcode::JB      => (I::Jb { offset: a? }, 1),
+            Opcode::EQ      => (I::Eq { a: a?, b: b? }, 2),
+            Opcode::NE      => (I::Ne { a: a?, b: b? }, 2),
+            Opcode::GT      => (I::Gt { a: a?, b: b? }, 2),
+            Opcode::LT      => (I::Lt { a: a?, b: b? }, 2),
+            Opcode::GTE     => (I::Gte { a: a?, b: b? }, 2),
+            Opcode::LTE     => (I::Lte { a: a?, b: b? }, 2),
+            Opcode::JE      => (I::Je { target: a? }, 1),
+            Opcode::JNE     => (I::Jne { target: a? }, 1),
+            Opcode::INC     => (I::Inc { reg: a? }, 1),
+            Opcode::DEC     => (I::Dec { reg: a? }, 1),
+            Opcode::SYSCALL => (I::Syscall { number: imm(x)? }, 1),
+            Opcode::ILL     => (I::Illegal { opcode: *code as u8 }, 0),
+        };
+        if [x, y, z].into_iter().flatten().count() != arity {
+            return Err(Error::InvalidOperands);
         }
-        Ok(parsed)
+        Ok(decoded)
     }
 
-    fn extract_operand(
-        token: &Token,
-        parsed: &mut Vec<u8>,
-    ) -> Result<(), Error> {
-        match token {
-            Token::Register { index: reg_index } => {
-                parsed.push(*reg_index);
-            }
-            Token::Integer { value } => {
-                let converted = *value as u16;
-                parsed.push((converted >> 8) as u8);
-                parsed.push(converted as u8);
-            }
-            _ => {
-                return Err(Error::OpcodeOperand);
-            }
+    /// Returns the name of the label given as an operand, if any.
+    pub fn label_operand(&self) -> Option<&str> {
+        let (x, y, z) = &self.operands;
+        [x, y, z].into_iter().find_map(|operand| match operand {
+            Some(Token::Label { name }) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Replaces label operands with the code offsets they point at.
+    pub fn resolve_labels(
+        &self,
+        labels: &BTreeMap<String, usize>,
+    ) -> Result<Instruction, Error> {
+        let resolve = |operand: &MaybeToken| match operand {
+            Some(Token::Label { name }) => match labels.get(name) {
+                Some(index) => Ok(Some(Token::Integer {
+                    value: (index * INSTRUCTION_LEN) as i32,
+                })),
+                None => Err(Error::UnknownLabel),
+            },
+            operand => Ok(operand.clone()),
         };
+        Ok(Instruction {
+            opcode: self.opcode.clone(),
+            operands: (
+                resolve(&self.operands.0)?,
+                resolve(&self.operands.1)?,
+                resolve(&self.operands.2)?,
+            ),
+        })
+    }
+
+    pub fn validate(&self, config: &Config) -> Result<(), Error> {
+        for operand in [&self.operands.0, &self.operands.1, &self.operands.2] {
+            if let Some(Token::Register { index }) = operand
+                && *index as usize >= config.registers
+            {
+                return Err(Error::RegisterOutOfRange);
+            }
+        }
         Ok(())
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+fn reg(operand: &MaybeToken) -> Result<u8, Error> {
+    match operand {
+        Some(Token::Register { index }) => Ok(*index),
+        Some(Token::Op { .. }) => Err(Error::OpcodeOperand),
+        _ => Err(Error::InvalidOperands),
+    }
+}
+
+/// Immediates are stored unsigned in 16 bits.
+fn imm(operand: &MaybeToken) -> Result<u16, Error> {
+    match operand {
+        Some(Token::Integer { value }) => {
+            u16::try_from(*value).map_err(|_| Error::ImmediateOutOfRange)
+        }
+        Some(Token::Op { .. }) => Err(Error::OpcodeOperand),
+        _ => Err(Error::InvalidOperands),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: Opcode },
     Register { index: u8 },
     Integer { value: i32 },
+    Label { name: String },
 }
 type MaybeToken = Option<Token>;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::header::HEADER_LEN;
     use crate::vm::Vm;
 
     #[test]
@@ -129,8 +381,137 @@ halt
         "#;
         let assembled = assembler.assemble(program).unwrap();
         let mut vm = Vm::default();
-        assert_eq!(assembled.len(), 24);
-        vm.push_bytes(assembled);
-        assert_eq!(vm.program.len(), 24);
+        assert_eq!(assembled.len(), HEADER_LEN + 24);
+        assert!(vm.load_bytecode(&assembled).is_ok());
+        assert_eq!(vm.program().len(), 24);
+    }
+
+    #[test]
+    fn test_assemble_register_count() {
+        let program = "load $8 #1\nhalt\n";
+        let mut assembler = Assembler::default();
+        let result = assembler.assemble(program);
+        assert!(matches!(result, Err(Error::RegisterOutOfRange)));
+
+        let config = Config {
+            registers: 16,
+            ..Config::default()
+        };
+        let mut assembler = Assembler::new(config);
+        let assembled = assembler.assemble(program).unwrap();
+        let mut vm = Vm::with_config(config);
+        assert!(vm.load_bytecode(&assembled).is_ok());
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.registers()[8], 1);
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let program = r#"
+        load $0 #3
+loop:   dec $0
+        load $1 @loop
+        load $2 @end
+        eq $0 $3
+        jne $1
+end:
+        halt
+"#;
+        let mut assembler = Assembler::default();
+        let assembled = assembler.assemble(program).unwrap();
+        assert_eq!(assembler.program().label_offset("loop"), Some(4));
+        assert_eq!(assembler.program().label_offset("end"), Some(24));
+        assert_eq!(&assembled[HEADER_LEN + 8..HEADER_LEN + 12], &[2, 1, 0, 4]);
+        assert_eq!(assembler.source_line(0), Some(2));
+        assert_eq!(assembler.source_line(24), Some(9));
+        assert_eq!(assembler.source_line(28), None);
+        let entry = assembler.debug_info().location(4).unwrap().entry;
+        assert_eq!((entry.line, entry.column), (3, 9));
+        assert_eq!(entry.label.as_deref(), Some("loop"));
+        let entry = assembler.debug_info().location(24).unwrap().entry;
+        assert_eq!((entry.column, entry.label.as_deref()), (9, Some("end")));
+
+        let mut vm = Vm::default();
+        assert!(vm.load_bytecode(&assembled).is_ok());
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.registers()[0], 0);
+    }
+
+    #[test]
+    fn test_assemble_embedded_debug_info() {
+        let mut assembler = Assembler::default();
+        assembler.set_file("test.rbvm");
+        assembler.set_embed_debug_info(true);
+        let assembled = assembler.assemble("load $0 #1\nhalt\n").unwrap();
+        let (header, code, section) =
+            Header::parse_sections(&assembled).unwrap();
+        assert!(header.debug_info);
+        assert_eq!(code.len(), 8);
+        let debug_info = DebugInfo::from_bytes(section.unwrap()).unwrap();
+        assert_eq!(&debug_info, assembler.debug_info());
+        assert_eq!(debug_info.location(4).unwrap().to_string(), "test.rbvm:2");
+    }
+
+    #[test]
+    fn test_assemble_optimized_debug_info() {
+        let mut assembler = Assembler::default();
+        assembler.set_optimize(true);
+        let assembled = assembler.assemble("start: pad\ninc $0\nhalt\n");
+        assert_eq!(assembled.unwrap().len(), HEADER_LEN + 8);
+        let entries = &assembler.debug_info().entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].offset, entries[0].line), (0, 2));
+        assert_eq!(entries[0].label.as_deref(), Some("start"));
+        assert_eq!((entries[1].offset, entries[1].line), (4, 3));
+    }
+
+    #[test]
+    fn test_assemble_label_errors() {
+        let mut assembler = Assembler::default();
+        let result = assembler.assemble("a: halt\na: halt\n");
+        assert!(matches!(result, Err(Error::DuplicateLabel)));
+        let result = assembler.assemble("load $0 @nowhere\n");
+        assert!(matches!(result, Err(Error::UnknownLabel)));
+        let result = assembler.assemble("load $0 #1 garbage\n");
+        assert!(matches!(result, Err(Error::ParseError)));
+    }
+
+    #[test]
+    fn test_instruction_decoded() {
+        let mut assembler = Assembler::default();
+        assembler
+            .assemble(
+                "load $1 @end
+add $0 $1 $2
+end: halt #3
+",
+            )
+            .unwrap();
+        let decoded: Vec<_> = assembler
+            .program()
+            .instrs
+            .iter()
+            .map(|instr| instr.decoded())
+            .collect();
+        assert!(matches!(decoded[0], Err(Error::InvalidOperands)));
+        assert_eq!(
+            decoded[1].as_ref().unwrap(),
+            &I::Add { a: 0, b: 1, dst: 2 }
+        );
+        assert_eq!(decoded[2].as_ref().unwrap(), &I::Halt { code: 3 });
+
+        let result = assembler.assemble("add $0 $1\n");
+        assert!(matches!(result, Err(Error::InvalidOperands)));
+        let result = assembler.assemble("jump #4\n");
+        assert!(matches!(result, Err(Error::InvalidOperands)));
+        let result = assembler.assemble("halt $0 $1\n");
+        assert!(matches!(result, Err(Error::InvalidOperands)));
+
+        let result = assembler.assemble("load $0 #70000\n");
+        assert!(matches!(result, Err(Error::ImmediateOutOfRange)));
+        let result = assembler.assemble("halt #70000\n");
+        assert!(matches!(result, Err(Error::ImmediateOutOfRange)));
+        let bytecode = assembler.assemble("load $0 #65535\n").unwrap();
+        assert_eq!(&bytecode[bytecode.len() - 3..], &[0, 255, 255]);
     }
 }
@@ -1,8 +1,9 @@
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::character::complete::{digit1, space1};
-use nom::combinator::{map, map_res, opt};
-use nom::sequence::preceded;
+use nom::character::complete::{alpha1, alphanumeric1, digit1, space1};
+use nom::combinator::{map, map_res, opt, recognize};
+use nom::multi::many0_count;
+use nom::sequence::{pair, preceded};
 use nom::{IResult, Parser};
 
 use super::{MaybeToken, Token};
@@ -23,8 +24,23 @@ pub fn integer_parser(input: &str) -> IResult<&str, Token> {
     map(spaced, |value| Token::Integer { value }).parse(input)
 }
 
+pub fn identifier_parser(input: &str) -> IResult<&str, &str> {
+    let head = alt((alpha1, tag("_")));
+    let tail = many0_count(alt((alphanumeric1, tag("_"))));
+    recognize(pair(head, tail)).parse(input)
+}
+
+pub fn label_parser(input: &str) -> IResult<&str, Token> {
+    let tagged = preceded(tag("@"), identifier_parser);
+    let spaced = preceded(space1, tagged);
+    map(spaced, |name: &str| Token::Label {
+        name: name.to_string(),
+    })
+    .parse(input)
+}
+
 pub fn operand_parser(input: &str) -> IResult<&str, Token> {
-    alt((register_parser, integer_parser)).parse(input)
+    alt((register_parser, integer_parser, label_parser)).parse(input)
 }
 
 pub fn oop(input: &str) -> IResult<&str, MaybeToken> {
@@ -61,4 +77,24 @@ mod tests {
         let result2 = integer_parser("#z");
         assert!(result2.is_err());
     }
+
+    #[test]
+    fn test_label_parser() {
+        let result0 = label_parser(" @loop_1");
+        assert!(result0.is_ok());
+        let (leftover, label) = result0.unwrap();
+        assert_eq!(leftover, "");
+        assert_eq!(
+            label,
+            Token::Label {
+                name: "loop_1".to_string()
+            }
+        );
+
+        let result1 = label_parser(" @1loop");
+        assert!(result1.is_err());
+
+        let result2 = label_parser(" loop");
+        assert!(result2.is_err());
+    }
 }
@@ -0,0 +1,369 @@
+use super::{Instruction, Program, Token};
+use crate::cfg::{Cfg, Known, Target};
+use crate::instruction::{DecodedInstruction as I, INSTRUCTION_LEN};
+use crate::opcodes::Opcode;
+
+/// Applies peephole optimisations until none of them changes the program:
+/// - replaces `add` of two registers holding known constants with a `load` of
+///   their sum,
+/// - removes `pad` instructions,
+/// - removes an `inc` directly followed by a `dec` of the same register, and
+///   the other way round,
+/// - removes jumps, conditional or not, to the next instruction,
+/// - removes code that can't be reached.
+///
+/// Constants and reachability come from the program's [`Cfg`], and programs
+/// with a jump it can't resolve are left as they are. Code is only removed
+/// when every jump goes through a register loaded from an `@label` operand:
+/// labels keep pointing at the same code, other addresses would move.
+///
+/// Returns the original index of every remaining instruction.
+pub fn optimize(program: &mut Program) -> Vec<usize> {
+    let mut origins: Vec<usize> = (0..program.instrs.len()).collect();
+    loop {
+        // Instructions that don't decode are left for the encoder to report.
+        let Ok(cfg) = Cfg::new(program) else {
+            return origins;
+        };
+        if !targets_known(&cfg) {
+            return origins;
+        }
+        let folded = fold_constants(program, &cfg);
+        let mut removed = vec![false; program.instrs.len()];
+        if relocatable(&cfg) {
+            remove_dead_code(&cfg, &mut removed);
+        }
+        if !folded && !removed.contains(&true) {
+            return origins;
+        }
+        remove(program, &removed, &mut origins);
+    }
+}
+
+/// Checks that every jump in reachable code lands on a known instruction.
+fn targets_known(cfg: &Cfg) -> bool {
+    cfg.blocks
+        .iter()
+        .filter(|block| block.reachable)
+        .all(|block| {
+            block.successors.iter().all(|edge| {
+                !matches!(edge.target, Target::Unknown | Target::Misaligned(_))
+            })
+        })
+}
+
+/// Checks that every jump in reachable code goes through a register holding
+/// a label address.
+fn relocatable(cfg: &Cfg) -> bool {
+    cfg.blocks
+        .iter()
+        .filter(|block| block.reachable)
+        .all(|block| {
+            let register = match cfg.block_instrs(block).last() {
+                Some(I::Jf { .. } | I::Jb { .. }) => return false,
+                Some(
+                    I::Jump { target } | I::Je { target } | I::Jne { target },
+                ) => *target,
+                _ => return true,
+            };
+            let known = cfg.known(block);
+            matches!(
+                known.last().and_then(|constants| constants.get(&register)),
+                Some(Known::Address(_))
+            )
+        })
+}
+
+/// Replaces additions of known integers with loads of their sum.
+fn fold_constants(program: &mut Program, cfg: &Cfg) -> bool {
+    let mut folded = false;
+    for block in cfg.blocks.iter().filter(|block| block.reachable) {
+        let first = block.start / INSTRUCTION_LEN;
+        let instrs = cfg.block_instrs(block).iter();
+        for (offset, (instr, known)) in instrs.zip(cfg.known(block)).enumerate()
+        {
+            let I::Add { a, b, dst } = *instr else {
+                continue;
+            };
+            let (Some(Known::Integer(a)), Some(Known::Integer(b))) =
+                (known.get(&a), known.get(&b))
+            else {
+                continue;
+            };
+            let Ok(sum) = u16::try_from(a + b) else {
+                continue;
+            };
+            program.instrs[first + offset] = Instruction::new(
+                Some(Token::Op { code: Opcode::LOAD }),
+                (
+                    Some(Token::Register { index: dst }),
+                    Some(Token::Integer { value: sum.into() }),
+                    None,
+                ),
+            );
+            folded = true;
+        }
+    }
+    folded
+}
+
+/// Marks unreachable code, padding, jumps to the next instruction and
+/// `inc`/`dec` pairs that cancel out.
+fn remove_dead_code(cfg: &Cfg, removed: &mut [bool]) {
+    for block in &cfg.blocks {
+        let first = block.start / INSTRUCTION_LEN;
+        let instrs = cfg.block_instrs(block);
+        if !block.reachable {
+            removed[first..first + instrs.len()].fill(true);
+            continue;
+        }
+        for (offset, (instr, known)) in
+            instrs.iter().zip(cfg.known(block)).enumerate()
+        {
+            let next = (first + offset + 1) * INSTRUCTION_LEN;
+            removed[first + offset] = match *instr {
+                I::Pad => true,
+                I::Jump { target } | I::Je { target } | I::Jne { target } => {
+                    known.get(&target) == Some(&Known::Address(next))
+                }
+                _ => false,
+            };
+        }
+        for (offset, pair) in instrs.windows(2).enumerate() {
+            let index = first + offset;
+            let cancels = matches!(
+                pair,
+                [I::Inc { reg: a }, I::Dec { reg: b }]
+                    | [I::Dec { reg: a }, I::Inc { reg: b }] if a == b
+            );
+            if cancels && !removed[index] {
+                removed[index] = true;
+                removed[index + 1] = true;
+            }
+        }
+    }
+}
+
+/// Drops the marked instructions. Labels of a removed instruction move to
+/// the next remaining one.
+fn remove(program: &mut Program, removed: &[bool], origins: &mut Vec<usize>) {
+    let mut new_index = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for is_removed in removed {
+        new_index.push(kept);
+        kept += usize::from(!is_removed);
+    }
+    new_index.push(kept);
+    for target in program.labels.values_mut() {
+        *target = new_index[(*target).min(removed.len())];
+    }
+    let mut index = 0;
+    program.instrs.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+    let mut index = 0;
+    origins.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::Vm;
+
+    fn assembled(source_code: &str) -> Program {
+        let mut assembler = Assembler::default();
+        assembler.assemble(source_code).unwrap();
+        assembler.program().clone()
+    }
+
+    fn optimized(source_code: &str) -> (Program, Vec<usize>) {
+        let mut program = assembled(source_code);
+        let origins = optimize(&mut program);
+        (program, origins)
+    }
+
+    /// Returns a VM loaded with the assembled program.
+    fn run(source_code: &str, optimize: bool) -> Vm {
+        let mut assembler = Assembler::default();
+        assembler.set_optimize(optimize);
+        let bytecode = assembler.assemble(source_code).unwrap();
+        let mut test_vm = Vm::default();
+        test_vm.load_bytecode(&bytecode).unwrap();
+        test_vm
+    }
+
+    #[test]
+    fn test_optimize() {
+        let source_code = "pad
+load $0 #40
+load $1 #2
+add $0 $1 $2
+start: inc $3
+dec $3
+load $4 @next
+jump $4
+next: halt #3
+inc $0
+end: halt
+";
+        let (program, origins) = optimized(source_code);
+        let mut expected = assembled(
+            "load $0 #40
+load $1 #2
+load $2 #42
+start: load $4 @next
+next: halt #3
+",
+        );
+        // Nothing jumps to `end`, so it ends up past the last instruction.
+        expected.labels.insert("end".to_string(), 5);
+        assert_eq!(program, expected);
+        assert_eq!(origins, [1, 2, 3, 6, 8]);
+    }
+
+    #[test]
+    fn test_optimize_keeps_targets() {
+        // A label on the `dec` keeps the pair. Once the `pad` is gone, `je`
+        // leads to the next instruction either way.
+        let source_code = "load $1 @skip
+inc $0
+back: dec $0
+je $1
+pad
+skip: halt
+";
+        let (program, origins) = optimized(source_code);
+        assert_eq!(origins, [0, 1, 2, 5]);
+        assert_eq!(program.labels["skip"], 3);
+
+        let source_code = "load $0 #4
+jf $0
+pad
+halt
+";
+        let (_, origins) = optimized(source_code);
+        assert_eq!(origins, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_optimize_across_blocks() {
+        // Both paths into `join` agree on `$0` and `$1`, but not on `$5`.
+        let source_code = "load $0 #1
+load $1 #2
+load $4 @join
+je $4
+load $5 #7
+join: add $0 $1 $2
+add $2 $5 $6
+halt
+";
+        let (program, origins) = optimized(source_code);
+        let expected = assembled(
+            "load $0 #1
+load $1 #2
+load $4 @join
+je $4
+load $5 #7
+join: load $2 #3
+add $2 $5 $6
+halt
+",
+        );
+        assert_eq!(program, expected);
+        assert_eq!(origins, [0, 1, 2, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_optimize_unresolved_jumps() {
+        // `jump $0` goes to `halt #2`, which isn't labelled.
+        let source_code = "load $0 #12
+jump $0
+halt #1
+halt #2
+";
+        let (_, origins) = optimized(source_code);
+        assert_eq!(origins, [0, 1, 2, 3]);
+        assert_eq!(run(source_code, true).run().unwrap(), 2);
+
+        // `jb $5` lands back on the `add` once `$0` has changed.
+        let source_code = "load $0 #1
+load $1 #2
+add $0 $1 $2
+load $6 #1
+inc $7
+eq $7 $6
+load $3 #44
+jne $3
+load $0 #10
+load $5 #36
+jb $5
+halt
+";
+        let (_, origins) = optimized(source_code);
+        assert_eq!(origins.len(), 12);
+        let mut test_vm = run(source_code, true);
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers()[2], 12);
+
+        // A syscall could overwrite the jump register.
+        let source_code = "load $1 @end
+syscall #3
+jump $1
+pad
+end: halt
+";
+        let (_, origins) = optimized(source_code);
+        assert_eq!(origins, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_optimize_keeps_code_after_ill() {
+        // Resuming after the fault runs the `inc`.
+        let source_code = "ill
+inc $0
+halt
+";
+        let (_, origins) = optimized(source_code);
+        assert_eq!(origins, [0, 1, 2]);
+        let mut test_vm = run(source_code, true);
+        assert!(test_vm.run().is_err());
+        test_vm.resume().unwrap();
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers()[0], 1);
+    }
+
+    #[test]
+    fn test_optimize_same_result() {
+        let source_code = "load $0 #3
+load $1 @loop
+load $5 #1
+add $5 $5 $6
+loop: pad
+inc $7
+dec $7
+dec $0
+eq $0 $2
+jne $1
+load $3 @done
+jump $3
+done: halt #5
+";
+        let mut expected = run(source_code, false);
+        let mut test_vm = run(source_code, true);
+        assert!(test_vm.program().len() < expected.program().len());
+
+        assert_eq!(test_vm.run().unwrap(), expected.run().unwrap());
+        // `$1` and `$3` hold code addresses, which moved.
+        for register in [0, 2, 4, 5, 6, 7] {
+            assert_eq!(
+                test_vm.registers()[register],
+                expected.registers()[register]
+            );
+        }
+    }
+}
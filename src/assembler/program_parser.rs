@@ -1,17 +1,36 @@
 use nom::branch::alt;
 use nom::bytes::complete::tag;
-use nom::combinator::map;
+use nom::character::complete::space0;
+use nom::combinator::{all_consuming, map, opt};
 use nom::multi::many1;
-use nom::sequence::terminated;
+use nom::sequence::{delimited, terminated};
 use nom::{IResult, Parser};
 
-use super::Program;
 use super::instruction_parsers::instr_parser;
+use super::operand_parsers::identifier_parser;
+use super::{Instruction, Program};
 
 pub fn program_parser(input: &str) -> IResult<&str, Program> {
     let with_newline = terminated(instr_parser, tag("\n"));
     let combined = alt((with_newline, instr_parser));
-    map(many1(combined), |instrs| Program { instrs }).parse(input)
+    map(many1(combined), |instrs| Program {
+        instrs,
+        ..Program::default()
+    })
+    .parse(input)
+}
+
+pub fn label_decl_parser(input: &str) -> IResult<&str, &str> {
+    terminated(identifier_parser, (tag(":"), space0)).parse(input)
+}
+
+/// Parses a single source line: an optional label declaration followed by an
+/// optional instruction. Blank lines yield neither.
+pub fn line_parser(
+    input: &str,
+) -> IResult<&str, (Option<&str>, Option<Instruction>)> {
+    let combined = (opt(label_decl_parser), opt(instr_parser));
+    all_consuming(delimited(space0, combined, space0)).parse(input)
 }
 
 #[cfg(test)]
@@ -72,4 +91,27 @@ mod tests {
         let result1 = bytecode.unwrap();
         assert_eq!(result1.len(), 4);
     }
+
+    #[test]
+    fn test_line_parser() {
+        let result0 = line_parser("loop: inc $0");
+        assert!(result0.is_ok());
+        let (_, (label, instr)) = result0.unwrap();
+        assert_eq!(label, Some("loop"));
+        assert!(instr.is_some());
+
+        let result1 = line_parser("  end:  ");
+        assert!(result1.is_ok());
+        let (_, (label, instr)) = result1.unwrap();
+        assert_eq!(label, Some("end"));
+        assert!(instr.is_none());
+
+        let result2 = line_parser("load $0 @end");
+        assert!(result2.is_ok());
+        let (_, (label, _)) = result2.unwrap();
+        assert_eq!(label, None);
+
+        let result3 = line_parser("load $0 #1 $");
+        assert!(result3.is_err());
+    }
 }
@@ -0,0 +1,95 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::vm::{Error, Vm};
+
+/// Timing of repeated runs of a program, see [`measure`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub runs: u32,
+    /// Instructions executed by a single run.
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    pub fn instructions_per_second(&self) -> f64 {
+        self.total() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn nanos_per_instruction(&self) -> f64 {
+        self.elapsed.as_nanos() as f64 / self.total() as f64
+    }
+
+    fn total(&self) -> u64 {
+        self.instructions * self.runs as u64
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} runs of {} instructions in {:.2?}",
+            self.runs, self.instructions, self.elapsed
+        )?;
+        write!(
+            f,
+            "{:.0} instructions/s, {:.2} ns/instruction",
+            self.instructions_per_second(),
+            self.nanos_per_instruction()
+        )
+    }
+}
+
+/// Returns the number of instructions a run of the program executes. The
+/// count comes from a profiled copy of the VM, `vm` itself isn't touched,
+/// but the copy shares its syscall handler.
+pub fn count_instructions(vm: &Vm) -> Result<u64, Error> {
+    let mut counter = vm.clone();
+    counter.reset();
+    counter.enable_profiling();
+    counter.run()?;
+    Ok(counter
+        .profile()
+        .map(|profile| profile.total())
+        .unwrap_or_default())
+}
+
+/// Runs the program `runs` times, resetting the VM before each run. The
+/// program runs once more beforehand to count its instructions.
+pub fn measure(vm: &mut Vm, runs: u32) -> Result<Measurement, Error> {
+    let instructions = count_instructions(vm)?;
+    let start = Instant::now();
+    for _ in 0..runs {
+        vm.reset();
+        vm.run()?;
+    }
+    Ok(Measurement {
+        runs,
+        instructions,
+        elapsed: start.elapsed(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_measure() {
+        // load $0 #3, dec $0, dec $0, halt
+        let program = vec![2, 0, 0, 3, 19, 0, 0, 0, 19, 0, 0, 0, 1, 0, 0, 0];
+        let mut test_vm = Vm::builder().program(program).build().unwrap();
+        assert_eq!(count_instructions(&test_vm).unwrap(), 4);
+        assert!(test_vm.profile().is_none());
+
+        let measurement = measure(&mut test_vm, 5).unwrap();
+        assert_eq!(measurement.runs, 5);
+        assert_eq!(measurement.instructions, 4);
+        assert_eq!(test_vm.registers()[0], 1);
+        let text = measurement.to_string();
+        assert!(text.starts_with("5 runs of 4 instructions in "));
+        assert!(text.contains(" ns/instruction"));
+    }
+}
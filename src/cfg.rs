@@ -0,0 +1,542 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use crate::assembler::{self, Program};
+use crate::instruction::{DecodedInstruction as I, INSTRUCTION_LEN};
+
+/// Value of a register known at assembly time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Known {
+    Integer(i64),
+    /// Code offset loaded from an `@label` operand.
+    Address(usize),
+}
+
+impl Known {
+    pub fn value(&self) -> i64 {
+        match *self {
+            Known::Integer(value) => value,
+            Known::Address(offset) => offset as i64,
+        }
+    }
+}
+
+/// Registers holding a value known at assembly time. Missing registers may
+/// hold anything.
+pub type Constants = BTreeMap<u8, Known>;
+
+/// Where an edge leads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// The block starting at this code offset.
+    Block(usize),
+    /// Past the last instruction, where the program stops.
+    End,
+    /// A known code offset at or past the end of the program.
+    OutOfBounds(usize),
+    /// A known code offset that isn't the start of an instruction.
+    Misaligned(usize),
+    /// Jump through a register whose value isn't known.
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    /// Falls through to the next instruction.
+    Next,
+    Jump,
+    /// Taken branch of `je` or `jne`.
+    Taken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: Target,
+}
+
+/// A straight run of instructions only entered at its start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Code offset of the first instruction.
+    pub start: usize,
+    /// Code offset just past the last instruction.
+    pub end: usize,
+    /// Labels pointing at the first instruction.
+    pub labels: Vec<String>,
+    pub successors: Vec<Edge>,
+    /// Whether any path from the entry point leads here.
+    pub reachable: bool,
+    /// Registers known whenever the block is entered.
+    pub entry: Constants,
+}
+
+/// Control-flow graph of a program.
+///
+/// Jump targets are found by propagating constants loaded into registers,
+/// including `@label` operands. Jumps through registers that don't hold a
+/// known value get an [`Target::Unknown`] edge and are assumed to land on a
+/// labelled block, as code addresses normally come from labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub instrs: Vec<I>,
+    /// Blocks sorted by their start offset.
+    pub blocks: Vec<Block>,
+    /// Indices of the loads of `@label` operands.
+    addresses: BTreeSet<usize>,
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Result<Cfg, assembler::Error> {
+        let instrs = program
+            .instrs
+            .iter()
+            .map(|instr| instr.resolve_labels(&program.labels)?.decoded())
+            .collect::<Result<Vec<_>, _>>()?;
+        let addresses = program
+            .instrs
+            .iter()
+            .enumerate()
+            .filter(|(_, instr)| instr.label_operand().is_some())
+            .map(|(index, _)| index)
+            .collect();
+        Ok(Cfg::build(instrs, &program.labels, addresses))
+    }
+
+    /// Builds the graph of decoded instructions. `labels` map names to
+    /// instruction indices, like [`Program::labels`].
+    pub fn from_instructions(
+        instrs: Vec<I>,
+        labels: &BTreeMap<String, usize>,
+    ) -> Cfg {
+        Cfg::build(instrs, labels, BTreeSet::new())
+    }
+
+    fn build(
+        mut instrs: Vec<I>,
+        labels: &BTreeMap<String, usize>,
+        mut addresses: BTreeSet<usize>,
+    ) -> Cfg {
+        let len = instrs.len();
+        let mut leaders: BTreeSet<usize> = labels
+            .values()
+            .copied()
+            .chain([0])
+            .filter(|index| *index < len)
+            .collect();
+        for (index, instr) in instrs.iter().enumerate() {
+            if ends_block(instr) && index + 1 < len {
+                leaders.insert(index + 1);
+            }
+        }
+        // Splitting a block at a newly found target can reveal more.
+        loop {
+            let mut cfg = Cfg {
+                blocks: leaders
+                    .iter()
+                    .map(|&leader| Block {
+                        start: leader * INSTRUCTION_LEN,
+                        end: leaders
+                            .range(leader + 1..)
+                            .next()
+                            .map_or(len, |next| *next)
+                            * INSTRUCTION_LEN,
+                        labels: vec![],
+                        successors: vec![],
+                        reachable: false,
+                        entry: Constants::new(),
+                    })
+                    .collect(),
+                instrs,
+                addresses,
+            };
+            cfg.propagate(labels);
+            let targets: Vec<usize> = cfg
+                .blocks
+                .iter()
+                .flat_map(|block| &block.successors)
+                .filter_map(|edge| match edge.target {
+                    Target::Block(offset) => Some(offset / INSTRUCTION_LEN),
+                    _ => None,
+                })
+                .filter(|index| !leaders.contains(index))
+                .collect();
+            if targets.is_empty() {
+                for (name, index) in labels {
+                    if let Some(block) = cfg.block_mut(index * INSTRUCTION_LEN)
+                    {
+                        block.labels.push(name.clone());
+                    }
+                }
+                return cfg;
+            }
+            leaders.extend(targets);
+            instrs = cfg.instrs;
+            addresses = cfg.addresses;
+        }
+    }
+
+    /// Returns the block starting at `offset`.
+    pub fn block(&self, offset: usize) -> Option<&Block> {
+        Some(&self.blocks[self.block_index(offset)?])
+    }
+
+    fn block_mut(&mut self, offset: usize) -> Option<&mut Block> {
+        let index = self.block_index(offset)?;
+        Some(&mut self.blocks[index])
+    }
+
+    fn block_index(&self, offset: usize) -> Option<usize> {
+        self.blocks
+            .binary_search_by_key(&offset, |block| block.start)
+            .ok()
+    }
+
+    /// Returns the instructions of a block.
+    pub fn block_instrs(&self, block: &Block) -> &[I] {
+        &self.instrs[block.start / INSTRUCTION_LEN..block.end / INSTRUCTION_LEN]
+    }
+
+    /// Returns the registers known before each instruction of a block.
+    pub fn known(&self, block: &Block) -> Vec<Constants> {
+        let mut constants = block.entry.clone();
+        (block.start / INSTRUCTION_LEN..block.end / INSTRUCTION_LEN)
+            .map(|index| {
+                let before = constants.clone();
+                self.transfer(index, &mut constants);
+                before
+            })
+            .collect()
+    }
+
+    /// Propagates constants from the entry point until the register state
+    /// at every block start stops changing, then records the successors.
+    fn propagate(&mut self, labels: &BTreeMap<String, usize>) {
+        let labelled: Vec<usize> = labels
+            .values()
+            .filter_map(|index| self.block_index(index * INSTRUCTION_LEN))
+            .collect();
+        let mut entry: Vec<Option<Constants>> = vec![None; self.blocks.len()];
+        let mut worklist = vec![];
+        if !self.blocks.is_empty() {
+            entry[0] = Some(Constants::new());
+            worklist.push(0);
+        }
+        while let Some(index) = worklist.pop() {
+            let mut constants = entry[index].clone().unwrap_or_default();
+            let successors = self.successors(index, &mut constants);
+            let targets =
+                successors.iter().flat_map(|edge| match edge.target {
+                    Target::Block(offset) => {
+                        self.block_index(offset).into_iter().collect()
+                    }
+                    Target::Unknown => labelled.clone(),
+                    _ => vec![],
+                });
+            for target in targets.collect::<Vec<_>>() {
+                let changed = match &mut entry[target] {
+                    Some(state) => {
+                        let before = state.len();
+                        state.retain(|register, value| {
+                            constants.get(register) == Some(value)
+                        });
+                        state.len() != before
+                    }
+                    state => {
+                        *state = Some(constants.clone());
+                        true
+                    }
+                };
+                if changed {
+                    worklist.push(target);
+                }
+            }
+        }
+        for (index, state) in entry.into_iter().enumerate() {
+            self.blocks[index].reachable = state.is_some();
+            let mut constants = state.unwrap_or_default();
+            self.blocks[index].entry = constants.clone();
+            self.blocks[index].successors =
+                self.successors(index, &mut constants);
+        }
+    }
+
+    /// Runs the block on `constants` and returns where it leads.
+    fn successors(&self, index: usize, constants: &mut Constants) -> Vec<Edge> {
+        let block = &self.blocks[index];
+        for index in block.start / INSTRUCTION_LEN..block.end / INSTRUCTION_LEN
+        {
+            self.transfer(index, constants);
+        }
+        let instrs = self.block_instrs(block);
+        let next = block.end;
+        let code_len = self.instrs.len() * INSTRUCTION_LEN;
+        let target = |kind, offset: Option<usize>| match offset {
+            None => Target::Unknown,
+            Some(offset) if offset >= code_len && kind == EdgeKind::Next => {
+                Target::End
+            }
+            Some(offset) if offset >= code_len => Target::OutOfBounds(offset),
+            Some(offset) if !offset.is_multiple_of(INSTRUCTION_LEN) => {
+                Target::Misaligned(offset)
+            }
+            Some(offset) => Target::Block(offset),
+        };
+        // Same conversion as the VM's jumps.
+        let value = |register: u8| {
+            constants.get(&register).map(|known| known.value() as usize)
+        };
+        let edge = |kind, offset| Edge {
+            kind,
+            target: target(kind, offset),
+        };
+        match instrs.last() {
+            Some(I::Jump { target }) => {
+                vec![edge(EdgeKind::Jump, value(*target))]
+            }
+            Some(I::Jf { offset }) => {
+                let offset =
+                    value(*offset).map(|value| next.wrapping_add(value));
+                vec![edge(EdgeKind::Jump, offset)]
+            }
+            Some(I::Jb { offset }) => {
+                let offset =
+                    value(*offset).map(|value| next.wrapping_sub(value));
+                vec![edge(EdgeKind::Jump, offset)]
+            }
+            Some(I::Je { target } | I::Jne { target }) => vec![
+                edge(EdgeKind::Taken, value(*target)),
+                edge(EdgeKind::Next, Some(next)),
+            ],
+            Some(I::Halt { .. }) => vec![],
+            _ => vec![edge(EdgeKind::Next, Some(next))],
+        }
+    }
+
+    /// Applies the register writes of the instruction at `index`. Only values
+    /// that fit in 32 bits are tracked, so the result doesn't depend on the VM
+    /// width.
+    fn transfer(&self, index: usize, constants: &mut Constants) {
+        let instr = self.instrs[index];
+        let fits = |value: &i64| i32::try_from(*value).is_ok();
+        let get = |register| constants.get(&register).map(Known::value);
+        let arithmetic = |a, b, op: fn(i64, i64) -> Option<i64>| {
+            op(get(a)?, get(b)?).filter(fits).map(Known::Integer)
+        };
+        let step = |register, delta| {
+            get(register)
+                .map(|value| value + delta)
+                .filter(fits)
+                .map(Known::Integer)
+        };
+        let value = match instr {
+            I::Load { imm, .. } if self.addresses.contains(&index) => {
+                Some(Known::Address(imm as usize))
+            }
+            I::Load { imm, .. } => Some(Known::Integer(imm as i64)),
+            I::Add { a, b, .. } => arithmetic(a, b, i64::checked_add),
+            I::Sub { a, b, .. } => arithmetic(a, b, i64::checked_sub),
+            I::Mul { a, b, .. } => arithmetic(a, b, i64::checked_mul),
+            I::Inc { reg } => step(reg, 1),
+            I::Dec { reg } => step(reg, -1),
+            _ => None,
+        };
+        for register in instr.writes() {
+            match value {
+                Some(value) => constants.insert(register, value),
+                None => constants.remove(&register),
+            };
+        }
+    }
+
+    /// Renders the graph in Graphviz DOT, one box per block listing its
+    /// instructions.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph cfg {\n");
+        dot.push_str("    node [shape=box, fontname=monospace];\n");
+        let (mut end, mut unknown) = (false, false);
+        for block in &self.blocks {
+            let mut text = String::new();
+            for label in &block.labels {
+                write!(text, "{label}:\\l").unwrap();
+            }
+            for (index, instr) in self.block_instrs(block).iter().enumerate() {
+                let offset = block.start + index * INSTRUCTION_LEN;
+                write!(text, "{offset:04}: {instr}\\l").unwrap();
+            }
+            let style = if block.reachable {
+                ""
+            } else {
+                ", style=dashed"
+            };
+            writeln!(dot, "    b{} [label=\"{text}\"{style}];", block.start)
+                .unwrap();
+        }
+        for block in &self.blocks {
+            for edge in &block.successors {
+                let target = match edge.target {
+                    Target::Block(offset) => format!("b{offset}"),
+                    Target::End | Target::OutOfBounds(_) => {
+                        end = true;
+                        "end".to_string()
+                    }
+                    Target::Misaligned(_) | Target::Unknown => {
+                        unknown = true;
+                        "unknown".to_string()
+                    }
+                };
+                let attributes = match edge.kind {
+                    EdgeKind::Taken => " [label=\"taken\"]",
+                    EdgeKind::Next | EdgeKind::Jump => "",
+                };
+                writeln!(dot, "    b{} -> {target}{attributes};", block.start)
+                    .unwrap();
+            }
+        }
+        if end {
+            dot.push_str("    end [shape=oval];\n");
+        }
+        if unknown {
+            dot.push_str("    unknown [shape=oval, label=\"?\"];\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+fn ends_block(instr: &I) -> bool {
+    matches!(
+        instr,
+        I::Jump { .. }
+            | I::Jf { .. }
+            | I::Jb { .. }
+            | I::Je { .. }
+            | I::Jne { .. }
+            | I::Halt { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn cfg(source_code: &str) -> Cfg {
+        let mut assembler = Assembler::default();
+        assembler.assemble(source_code).unwrap();
+        Cfg::new(assembler.program()).unwrap()
+    }
+
+    fn edge(kind: EdgeKind, target: Target) -> Edge {
+        Edge { kind, target }
+    }
+
+    #[test]
+    fn test_cfg_loop() {
+        let graph = cfg("load $0 #3
+load $1 @loop
+loop: dec $0
+eq $0 $2
+jne $1
+halt
+");
+        let bounds: Vec<_> = graph
+            .blocks
+            .iter()
+            .map(|block| (block.start, block.end))
+            .collect();
+        assert_eq!(bounds, [(0, 8), (8, 20), (20, 24)]);
+        assert_eq!(graph.blocks[1].labels, ["loop"]);
+        assert_eq!(
+            graph.blocks[0].successors,
+            [edge(EdgeKind::Next, Target::Block(8))]
+        );
+        assert_eq!(
+            graph.blocks[1].successors,
+            [
+                edge(EdgeKind::Taken, Target::Block(8)),
+                edge(EdgeKind::Next, Target::Block(20)),
+            ]
+        );
+        assert!(graph.blocks[2].successors.is_empty());
+        assert!(graph.blocks.iter().all(|block| block.reachable));
+        // `$0` differs between the first pass and the following ones.
+        assert_eq!(
+            graph.blocks[1].entry,
+            Constants::from([(1, Known::Address(8))])
+        );
+        let known = graph.known(&graph.blocks[0]);
+        assert_eq!(known, [Constants::new(), [(0, Known::Integer(3))].into()]);
+    }
+
+    #[test]
+    fn test_cfg_targets() {
+        // The jump target is computed, `jf` skips the `inc` that isn't
+        // reachable otherwise, and `$3` is never loaded.
+        let graph = cfg("load $0 #12
+load $1 #8
+add $0 $1 $2
+jump $2
+halt
+load $4 #4
+jf $4
+inc $0
+jump $3
+");
+        let starts: Vec<_> =
+            graph.blocks.iter().map(|block| block.start).collect();
+        assert_eq!(starts, [0, 16, 20, 28, 32]);
+        assert_eq!(
+            graph.blocks[0].successors,
+            [edge(EdgeKind::Jump, Target::Block(20))]
+        );
+        assert!(!graph.blocks[1].reachable);
+        assert_eq!(
+            graph.blocks[2].successors,
+            [edge(EdgeKind::Jump, Target::Block(32))]
+        );
+        assert!(!graph.blocks[3].reachable);
+        assert_eq!(
+            graph.blocks[4].successors,
+            [edge(EdgeKind::Jump, Target::Unknown)]
+        );
+
+        let graph = cfg("load $0 #6\njump $0\nload $0 #40\njump $0\n");
+        assert_eq!(
+            graph.blocks[0].successors,
+            [edge(EdgeKind::Jump, Target::Misaligned(6))]
+        );
+        assert_eq!(
+            graph.blocks[1].successors,
+            [edge(EdgeKind::Jump, Target::OutOfBounds(40))]
+        );
+    }
+
+    #[test]
+    fn test_cfg_unknown_jump_reaches_labels() {
+        // `there` is only reachable through the jump to an unknown address.
+        let graph = cfg("jump $1\nload $0 #1\nthere: halt\n");
+        assert_eq!(
+            graph.blocks[0].successors,
+            [edge(EdgeKind::Jump, Target::Unknown)]
+        );
+        assert!(!graph.blocks[1].reachable);
+        assert!(graph.block(8).unwrap().reachable);
+    }
+
+    #[test]
+    fn test_cfg_to_dot() {
+        let dot = cfg("loop: load $0 @loop\njne $0\nhalt\n").to_dot();
+        assert_eq!(
+            dot,
+            r#"digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label="loop:\l0000: load $0 #0\l0004: jne $0\l"];
+    b8 [label="0008: halt\l"];
+    b0 -> b0 [label="taken"];
+    b0 -> b8;
+}
+"#
+        );
+    }
+}
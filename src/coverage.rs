@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::instruction::{DecodedInstruction as I, INSTRUCTION_LEN};
+use crate::profiler::Profile;
+
+/// Directions taken by a conditional jump.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Branch {
+    pub pc: usize,
+    pub line: usize,
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl Branch {
+    fn executed(&self) -> bool {
+        self.taken + self.not_taken > 0
+    }
+}
+
+/// Per-source-line coverage computed from the counters of a [`Profile`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Coverage {
+    /// Executions of every source line holding an instruction.
+    pub lines: BTreeMap<usize, u64>,
+    pub branches: Vec<Branch>,
+}
+
+impl Coverage {
+    pub fn new(
+        profile: &Profile,
+        program: &[u8],
+        source_line: impl Fn(usize) -> Option<usize>,
+    ) -> Self {
+        let mut coverage = Coverage::default();
+        for pc in (0..program.len()).step_by(INSTRUCTION_LEN) {
+            let Some(line) = source_line(pc) else {
+                continue;
+            };
+            let count = profile.count(pc);
+            *coverage.lines.entry(line).or_default() += count;
+            let instr = I::decode(&program[pc..]);
+            if matches!(instr, I::Je { .. } | I::Jne { .. }) {
+                let taken: u64 = profile
+                    .edges
+                    .range((pc, 0)..=(pc, usize::MAX))
+                    .map(|(_, count)| count)
+                    .sum();
+                coverage.branches.push(Branch {
+                    pc,
+                    line,
+                    taken,
+                    not_taken: count - taken,
+                });
+            }
+        }
+        coverage
+    }
+
+    /// Annotates every source line with its execution count, `#####` marks
+    /// lines that never ran.
+    pub fn to_text(&self, source_code: &str) -> String {
+        let mut text = String::new();
+        for (index, source) in source_code.lines().enumerate() {
+            let line = index + 1;
+            let count = match self.lines.get(&line) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = write!(text, "{count:>8}:{line:>5}: {source}");
+            for branch in self.branches.iter().filter(|b| b.line == line) {
+                let _ = write!(
+                    text,
+                    "  [taken {}, not taken {}]",
+                    branch.taken, branch.not_taken
+                );
+            }
+            text.push('\n');
+        }
+        let (lines_hit, lines_found) = self.lines_hit();
+        let (branches_hit, branches_found) = self.branches_hit();
+        let _ = writeln!(text, "\nLines: {lines_hit}/{lines_found}");
+        let _ = writeln!(text, "Branches: {branches_hit}/{branches_found}");
+        text
+    }
+
+    /// Renders an lcov tracefile for the source file at `path`.
+    pub fn to_lcov(&self, path: &str) -> String {
+        let mut lcov = format!("TN:\nSF:{path}\n");
+        for (index, branch) in self.branches.iter().enumerate() {
+            for (direction, count) in
+                [branch.taken, branch.not_taken].into_iter().enumerate()
+            {
+                let count = match branch.executed() {
+                    true => count.to_string(),
+                    false => "-".to_string(),
+                };
+                let _ = writeln!(
+                    lcov,
+                    "BRDA:{},{index},{direction},{count}",
+                    branch.line
+                );
+            }
+        }
+        let (branches_hit, branches_found) = self.branches_hit();
+        let _ = writeln!(lcov, "BRF:{branches_found}\nBRH:{branches_hit}");
+        for (line, count) in &self.lines {
+            let _ = writeln!(lcov, "DA:{line},{count}");
+        }
+        let (lines_hit, lines_found) = self.lines_hit();
+        let _ = writeln!(lcov, "LF:{lines_found}\nLH:{lines_hit}");
+        lcov.push_str("end_of_record\n");
+        lcov
+    }
+
+    fn lines_hit(&self) -> (usize, usize) {
+        let hit = self.lines.values().filter(|count| **count > 0).count();
+        (hit, self.lines.len())
+    }
+
+    /// Counts each direction of every conditional jump separately.
+    fn branches_hit(&self) -> (usize, usize) {
+        let hit = self
+            .branches
+            .iter()
+            .map(|branch| {
+                (branch.taken > 0) as usize + (branch.not_taken > 0) as usize
+            })
+            .sum();
+        (hit, self.branches.len() * 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::Vm;
+
+    const PROGRAM: &str = "load $0 #2
+load $1 @loop
+load $2 @end
+loop: dec $0
+eq $0 $3
+je $2
+jne $1
+halt #1
+end: halt
+";
+
+    fn get_test_coverage() -> Coverage {
+        let mut assembler = Assembler::default();
+        let bytecode = assembler.assemble(PROGRAM).unwrap();
+        let mut vm = Vm::default();
+        vm.load_bytecode(&bytecode).unwrap();
+        vm.enable_profiling();
+        assert!(vm.run().is_ok());
+        Coverage::new(vm.profile().unwrap(), vm.program(), |pc| {
+            assembler.source_line(pc)
+        })
+    }
+
+    #[test]
+    fn test_coverage_lines_and_branches() {
+        let coverage = get_test_coverage();
+        assert_eq!(coverage.lines.len(), 9);
+        assert_eq!(coverage.lines[&4], 2);
+        assert_eq!(coverage.lines[&8], 0);
+        assert_eq!(
+            coverage.branches,
+            vec![
+                Branch {
+                    pc: 20,
+                    line: 6,
+                    taken: 1,
+                    not_taken: 1,
+                },
+                Branch {
+                    pc: 24,
+                    line: 7,
+                    taken: 1,
+                    not_taken: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coverage_text() {
+        let text = get_test_coverage().to_text(PROGRAM);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[3], "       2:    4: loop: dec $0");
+        assert_eq!(lines[5], "       2:    6: je $2  [taken 1, not taken 1]");
+        assert_eq!(lines[7], "   #####:    8: halt #1");
+        assert!(text.ends_with("Lines: 8/9\nBranches: 3/4\n"));
+    }
+
+    #[test]
+    fn test_coverage_lcov() {
+        let lcov = get_test_coverage().to_lcov("test.rbvm");
+        assert!(lcov.starts_with("TN:\nSF:test.rbvm\nBRDA:6,0,0,1\n"));
+        assert!(lcov.contains("BRDA:7,1,1,0\nBRF:4\nBRH:3\n"));
+        assert!(lcov.contains("DA:8,0\n"));
+        assert!(lcov.ends_with("LF:9\nLH:8\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"RBDI";
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Truncated,
+    BadMagic,
+    InvalidField,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "debug info is truncated"),
+            Error::BadMagic => write!(f, "debug info doesn't start with RBDI"),
+            Error::InvalidField => write!(f, "debug info has an invalid field"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Source position of the instruction at a code offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub offset: usize,
+    /// 1-based line and column of the instruction mnemonic.
+    pub line: usize,
+    pub column: usize,
+    /// The first label pointing at the instruction, if any.
+    pub label: Option<String>,
+}
+
+/// Maps code offsets of an assembled program back to its source file.
+///
+/// The table can be embedded in the bytecode container:
+/// ```text
+/// +---------------------------------------------------------+
+/// | Magic (4 bytes) | File (16-bit length, UTF-8) | Entries |
+/// +---------------------------------------------------------+
+/// ```
+/// preceded by a 32-bit entry count, every entry holds a 32-bit offset and
+/// line, a 16-bit column and a 16-bit length prefixed label, empty when the
+/// instruction has none. All integers are big-endian.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DebugInfo {
+    pub file: String,
+    /// Entries sorted by offset.
+    pub entries: Vec<Entry>,
+}
+
+/// A source position rendered as `file:line`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub entry: &'a Entry,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file {
+            "" => write!(f, "line {}", self.entry.line),
+            file => write!(f, "{file}:{}", self.entry.line),
+        }
+    }
+}
+
+impl DebugInfo {
+    pub fn new(file: impl Into<String>) -> Self {
+        DebugInfo {
+            file: file.into(),
+            entries: vec![],
+        }
+    }
+
+    /// Returns the source position of the instruction at `offset`.
+    pub fn location(&self, offset: usize) -> Option<Location<'_>> {
+        let index = self
+            .entries
+            .binary_search_by_key(&offset, |entry| entry.offset)
+            .ok()?;
+        Some(Location {
+            file: &self.file,
+            entry: &self.entries[index],
+        })
+    }
+
+    /// Returns the 1-based source line of the instruction at `offset`.
+    pub fn line(&self, offset: usize) -> Option<usize> {
+        self.location(offset).map(|location| location.entry.line)
+    }
+
+    /// Returns the code offset a label points at.
+    pub fn label_offset(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .find(|entry| entry.label.as_deref() == Some(name))
+            .map(|entry| entry.offset)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        push_string(&mut bytes, &self.file);
+        bytes.extend((self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            bytes.extend((entry.offset as u32).to_be_bytes());
+            bytes.extend((entry.line as u32).to_be_bytes());
+            bytes.extend((entry.column as u16).to_be_bytes());
+            push_string(&mut bytes, entry.label.as_deref().unwrap_or_default());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        if reader.take::<4>()? != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        let file = reader.string()?;
+        let count = u32::from_be_bytes(reader.take()?);
+        let mut entries = vec![];
+        for _ in 0..count {
+            let offset = u32::from_be_bytes(reader.take()?) as usize;
+            let line = u32::from_be_bytes(reader.take()?) as usize;
+            let column = u16::from_be_bytes(reader.take()?) as usize;
+            let label =
+                Some(reader.string()?).filter(|label| !label.is_empty());
+            entries.push(Entry {
+                offset,
+                line,
+                column,
+                label,
+            });
+        }
+        if !reader.bytes.is_empty()
+            || !entries.is_sorted_by_key(|entry| entry.offset)
+        {
+            return Err(Error::InvalidField);
+        }
+        Ok(DebugInfo { file, entries })
+    }
+}
+
+fn push_string(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend((value.len() as u16).to_be_bytes());
+    bytes.extend(value.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let (taken, rest) =
+            self.bytes.split_first_chunk().ok_or(Error::Truncated)?;
+        self.bytes = rest;
+        Ok(*taken)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = u16::from_be_bytes(self.take()?) as usize;
+        if self.bytes.len() < len {
+            return Err(Error::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        String::from_utf8(taken.to_vec()).map_err(|_| Error::InvalidField)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_debug_info() -> DebugInfo {
+        DebugInfo {
+            file: "examples/countdown.rbvm".to_string(),
+            entries: vec![
+                Entry {
+                    offset: 0,
+                    line: 1,
+                    column: 1,
+                    label: None,
+                },
+                Entry {
+                    offset: 4,
+                    line: 3,
+                    column: 7,
+                    label: Some("loop".to_string()),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_debug_info_location() {
+        let debug_info = get_test_debug_info();
+        let location = debug_info.location(4).unwrap();
+        assert_eq!(location.to_string(), "examples/countdown.rbvm:3");
+        assert_eq!(location.entry.label.as_deref(), Some("loop"));
+        assert_eq!(debug_info.line(0), Some(1));
+        assert_eq!(debug_info.line(2), None);
+        assert_eq!(debug_info.line(8), None);
+    }
+
+    #[test]
+    fn test_debug_info_roundtrip() {
+        let debug_info = get_test_debug_info();
+        let bytes = debug_info.to_bytes();
+        assert_eq!(DebugInfo::from_bytes(&bytes), Ok(debug_info));
+        assert_eq!(
+            DebugInfo::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(Error::Truncated)
+        );
+        assert_eq!(DebugInfo::from_bytes(&[0; 10]), Err(Error::BadMagic));
+    }
+}
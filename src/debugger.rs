@@ -0,0 +1,430 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::assembler::Assembler;
+use crate::disassembler::disassemble_instruction;
+use crate::instruction::INSTRUCTION_LEN;
+use crate::trace::History;
+use crate::vm::{Error, Vm};
+use crate::watchpoints::Watchpoint;
+
+/// Instructions shown on either side of the pc by `list`.
+const LIST_CONTEXT: usize = 3;
+/// Executed instructions that can be stepped back over.
+const HISTORY_LEN: usize = 100_000;
+
+const HELP: &str = "\
+break <loc>     set a breakpoint at a label or an address (alias: b)
+delete <loc>    remove a breakpoint
+step            execute one instruction (alias: s)
+continue        run until a breakpoint or the end (alias: c)
+back            undo the last instruction (alias: step-back)
+reverse         run backwards to the previous breakpoint (alias: rc)
+until <loc>     run to a label or an address (alias: u)
+watch <expr>    stop when a register is written, e.g. `watch $1 > 3`
+unwatch <$r>    remove the watchpoints on a register
+registers       print the registers (alias: r)
+flags           print the equal flag and the remainder
+pc              print the current instruction
+list            print the disassembly around the pc (alias: l)
+quit            leave the debugger (alias: q)";
+
+#[derive(Debug)]
+pub struct Debugger {
+    vm: Vm,
+    assembler: Assembler,
+    source: Vec<String>,
+    breakpoints: BTreeSet<usize>,
+    history: Rc<RefCell<History>>,
+    finished: bool,
+}
+
+impl Debugger {
+    /// Wraps a VM loaded with the output of `assembler`, which must have
+    /// assembled `source_code`. For bytecode loaded as is, pass an empty
+    /// assembler and lines and labels come from the embedded debug info.
+    pub fn new(mut vm: Vm, assembler: Assembler, source_code: &str) -> Self {
+        let history = Rc::new(RefCell::new(History::new(HISTORY_LEN)));
+        vm.set_trace_sink(history.clone());
+        Debugger {
+            vm,
+            assembler,
+            source: source_code.lines().map(str::to_string).collect(),
+            breakpoints: BTreeSet::new(),
+            history,
+            finished: false,
+        }
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("RBVM (0.1.0) debugger, type `help` for commands");
+        let mut stdout = io::stdout();
+        self.print_location(&mut stdout)?;
+        loop {
+            let mut buffer = String::new();
+            print!("(rbvm) ");
+            stdout.flush()?;
+            if io::stdin().read_line(&mut buffer)? == 0 {
+                return Ok(());
+            }
+            if !self.execute(buffer.trim(), &mut stdout)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes a single debugger command. Returns `false` once the user asks
+    /// to quit.
+    pub fn execute(
+        &mut self,
+        command: &str,
+        out: &mut impl Write,
+    ) -> io::Result<bool> {
+        let mut words = command.split_whitespace();
+        let (name, argument) = (words.next().unwrap_or_default(), words.next());
+        match (name, argument) {
+            ("", _) => {}
+            ("break" | "b", Some(location)) => match self.resolve(location) {
+                Some(offset) => {
+                    self.breakpoints.insert(offset);
+                    writeln!(out, "Breakpoint at {}", self.describe(offset))?
+                }
+                None => writeln!(out, "Unknown location `{location}`")?,
+            },
+            ("delete", Some(location)) => match self.resolve(location) {
+                Some(offset) if self.breakpoints.remove(&offset) => {
+                    writeln!(out, "Breakpoint removed")?
+                }
+                _ => writeln!(out, "No breakpoint at `{location}`")?,
+            },
+            ("step" | "s", None) => {
+                if self.step(out)? {
+                    self.print_location(out)?;
+                }
+            }
+            ("continue" | "c", None) => self.resume(None, out)?,
+            ("back" | "step-back", None) => {
+                if self.step_back(out)? {
+                    self.print_location(out)?;
+                }
+            }
+            ("reverse" | "reverse-continue" | "rc", None) => {
+                self.reverse(out)?
+            }
+            ("until" | "u", Some(location)) => match self.resolve(location) {
+                Some(offset) => self.resume(Some(offset), out)?,
+                None => writeln!(out, "Unknown location `{location}`")?,
+            },
+            ("watch", Some(_)) => {
+                let expression = command["watch".len()..].trim();
+                match expression.parse::<Watchpoint>() {
+                    Ok(watchpoint) => {
+                        match self.vm.add_watchpoint(watchpoint) {
+                            Ok(()) => writeln!(out, "Watching {expression}")?,
+                            Err(err) => writeln!(out, "{err}")?,
+                        }
+                    }
+                    Err(err) => writeln!(out, "{err}")?,
+                }
+            }
+            ("unwatch", Some(register)) => {
+                match register.strip_prefix('$').map(str::parse::<u8>) {
+                    Some(Ok(register)) => self.vm.remove_watchpoints(register),
+                    _ => writeln!(out, "Expected a register")?,
+                }
+            }
+            ("registers" | "r", None) => {
+                writeln!(out, "{:?}", self.vm.registers())?
+            }
+            ("flags", None) => writeln!(
+                out,
+                "equal_flag: {}, remainder: {}",
+                self.vm.equal_flag(),
+                self.vm.remainder()
+            )?,
+            ("pc", None) => self.print_location(out)?,
+            ("list" | "l", None) => self.list(out)?,
+            ("help", None) => writeln!(out, "{HELP}")?,
+            ("quit" | "q", None) => return Ok(false),
+            _ => writeln!(out, "Unknown command `{command}`, try `help`")?,
+        }
+        Ok(true)
+    }
+
+    /// Parses a label name or a decimal/hexadecimal address into a code
+    /// offset that starts an instruction.
+    fn resolve(&self, location: &str) -> Option<usize> {
+        let offset = match location.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => location.parse().ok(),
+        }
+        .or_else(|| self.assembler.program().label_offset(location))
+        .or_else(|| self.vm.debug_info()?.label_offset(location))?;
+        let aligned = offset % INSTRUCTION_LEN == 0;
+        (aligned && offset < self.vm.program().len()).then_some(offset)
+    }
+
+    /// Executes one instruction. Returns `false` if execution can't go on.
+    fn step(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        if self.finished {
+            writeln!(out, "The program has finished")?;
+            return Ok(false);
+        }
+        match self.vm.run_once() {
+            Ok(()) => return Ok(true),
+            Err(Error::WatchpointHit) => {
+                for hit in self.vm.take_watch_hits() {
+                    match self.source_line(hit.pc) {
+                        Some(line) => {
+                            writeln!(out, "Watchpoint {hit} (line {line})")?
+                        }
+                        None => writeln!(out, "Watchpoint {hit}")?,
+                    }
+                }
+                self.print_location(out)?
+            }
+            Err(Error::HaltEncountered | Error::ReachedEof) => {
+                self.finished = true;
+                let exit_code = self.vm.exit_code();
+                writeln!(out, "Program finished with exit code {exit_code}")?
+            }
+            Err(err) => {
+                // Illegal opcodes are skipped, other faults end the program.
+                self.finished = !matches!(err, Error::IllegalOpcode(_));
+                if !self.finished {
+                    let _ = self.vm.resume();
+                }
+                writeln!(out, "Error: {err}")?
+            }
+        }
+        Ok(false)
+    }
+
+    fn resume(
+        &mut self,
+        until: Option<usize>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        while self.step(out)? {
+            let pc = self.vm.pc();
+            if self.breakpoints.contains(&pc) || until == Some(pc) {
+                return self.print_location(out);
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes the last executed instruction. Returns `false` if the history
+    /// is exhausted.
+    fn step_back(&mut self, out: &mut impl Write) -> io::Result<bool> {
+        let Some(record) = self.history.borrow_mut().pop() else {
+            writeln!(out, "No more history")?;
+            return Ok(false);
+        };
+        self.vm.rewind(&record);
+        self.finished = false;
+        Ok(true)
+    }
+
+    fn reverse(&mut self, out: &mut impl Write) -> io::Result<()> {
+        while self.step_back(out)? {
+            if self.breakpoints.contains(&self.vm.pc()) {
+                break;
+            }
+        }
+        self.print_location(out)
+    }
+
+    fn print_location(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.vm.pc() < self.vm.program().len() {
+            writeln!(out, "=> {}", self.describe(self.vm.pc()))?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.vm.pc() - self.vm.pc() % INSTRUCTION_LEN;
+        let start = pc.saturating_sub(LIST_CONTEXT * INSTRUCTION_LEN);
+        let end = (pc + (LIST_CONTEXT + 1) * INSTRUCTION_LEN)
+            .min(self.vm.program().len());
+        for offset in (start..end).step_by(INSTRUCTION_LEN) {
+            let marker =
+                match (offset == pc, self.breakpoints.contains(&offset)) {
+                    (true, _) => "=>",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+            writeln!(out, "{marker} {}", self.describe(offset))?;
+        }
+        Ok(())
+    }
+
+    /// Renders the instruction at `offset` along with its source line.
+    fn describe(&self, offset: usize) -> String {
+        let instr = disassemble_instruction(&self.vm.program()[offset..]);
+        let described = format!("{offset:04}: {instr}");
+        let Some(line) = self.source_line(offset) else {
+            return described;
+        };
+        match self.source.get(line - 1) {
+            Some(source) => {
+                format!("{described:<24}; line {line}: {}", source.trim())
+            }
+            None => format!("{described:<24}; line {line}"),
+        }
+    }
+
+    /// Returns the source line of the instruction at `offset`, from the
+    /// assembler or else from debug info embedded in the bytecode.
+    fn source_line(&self, offset: usize) -> Option<usize> {
+        self.assembler
+            .source_line(offset)
+            .or_else(|| self.vm.debug_info()?.line(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_debugger(source_code: &str) -> Debugger {
+        let mut assembler = Assembler::default();
+        let bytecode = assembler.assemble(source_code).unwrap();
+        let mut vm = Vm::default();
+        vm.load_bytecode(&bytecode).unwrap();
+        Debugger::new(vm, assembler, source_code)
+    }
+
+    fn execute(debugger: &mut Debugger, command: &str) -> String {
+        let mut out = vec![];
+        assert!(debugger.execute(command, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    const PROGRAM: &str = "load $0 #3
+load $1 @loop
+loop: dec $0
+eq $0 $2
+jne $1
+halt #7
+";
+
+    #[test]
+    fn test_break_and_continue() {
+        let mut debugger = get_test_debugger(PROGRAM);
+        let output = execute(&mut debugger, "break loop");
+        assert!(output.starts_with("Breakpoint at 0008: dec $0"));
+        assert!(output.ends_with("; line 3: loop: dec $0\n"));
+        execute(&mut debugger, "c");
+        assert_eq!(debugger.vm().pc(), 8);
+        assert_eq!(debugger.vm().registers()[0], 3);
+        execute(&mut debugger, "continue");
+        assert_eq!(debugger.vm().registers()[0], 2);
+
+        execute(&mut debugger, "delete 8");
+        let output = execute(&mut debugger, "continue");
+        assert_eq!(output, "Program finished with exit code 7\n");
+        let output = execute(&mut debugger, "step");
+        assert_eq!(output, "The program has finished\n");
+    }
+
+    #[test]
+    fn test_embedded_debug_info() {
+        let mut assembler = Assembler::default();
+        assembler.set_embed_debug_info(true);
+        let bytecode = assembler.assemble(PROGRAM).unwrap();
+        let mut vm = Vm::default();
+        vm.load_bytecode(&bytecode).unwrap();
+        let mut debugger = Debugger::new(vm, Assembler::default(), PROGRAM);
+        let output = execute(&mut debugger, "break loop");
+        assert!(output.ends_with("; line 3: loop: dec $0\n"));
+
+        // Without the source only the line number is left.
+        let mut vm = Vm::default();
+        vm.load_bytecode(&bytecode).unwrap();
+        let mut debugger = Debugger::new(vm, Assembler::default(), "");
+        let output = execute(&mut debugger, "break loop");
+        assert!(output.ends_with("; line 3\n"));
+    }
+
+    #[test]
+    fn test_step_and_until() {
+        let mut debugger = get_test_debugger(PROGRAM);
+        let output = execute(&mut debugger, "step");
+        assert!(output.starts_with("=> 0004: load $1 #8"));
+        execute(&mut debugger, "until 0x10");
+        assert_eq!(debugger.vm().pc(), 16);
+        let output = execute(&mut debugger, "list");
+        assert!(output.contains("=> 0016: jne $1"));
+        assert_eq!(output.lines().count(), 5);
+        let output = execute(&mut debugger, "flags");
+        assert_eq!(output, "equal_flag: false, remainder: 0\n");
+    }
+
+    #[test]
+    fn test_invalid_locations() {
+        let mut debugger = get_test_debugger(PROGRAM);
+        let output = execute(&mut debugger, "break 6");
+        assert_eq!(output, "Unknown location `6`\n");
+        let output = execute(&mut debugger, "break nowhere");
+        assert_eq!(output, "Unknown location `nowhere`\n");
+        let mut out = vec![];
+        assert!(!debugger.execute("quit", &mut out).unwrap());
+    }
+
+    #[test]
+    fn test_watch() {
+        let mut debugger = get_test_debugger(PROGRAM);
+        let output = execute(&mut debugger, "watch $0 < 2");
+        assert_eq!(output, "Watching $0 < 2\n");
+        let output = execute(&mut debugger, "watch $8");
+        assert_eq!(output, "register $8 doesn't exist\n");
+        let output = execute(&mut debugger, "continue");
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Watchpoint $0: 2 -> 1 at 0008 (line 3)")
+        );
+        assert!(lines.next().unwrap().starts_with("=> 0012: eq $0 $2"));
+        execute(&mut debugger, "unwatch $0");
+        let output = execute(&mut debugger, "continue");
+        assert_eq!(output, "Program finished with exit code 7\n");
+    }
+
+    #[test]
+    fn test_step_back_and_reverse() {
+        let mut debugger = get_test_debugger(PROGRAM);
+        let output = execute(&mut debugger, "back");
+        assert_eq!(output, "No more history\n");
+        execute(&mut debugger, "step");
+        execute(&mut debugger, "step");
+        execute(&mut debugger, "step");
+        assert_eq!(debugger.vm().registers()[0], 2);
+        let output = execute(&mut debugger, "back");
+        assert!(output.starts_with("=> 0008: dec $0"));
+        assert_eq!(debugger.vm().registers()[0], 3);
+
+        execute(&mut debugger, "break 12");
+        let output = execute(&mut debugger, "continue");
+        assert!(output.starts_with("=> 0012"));
+        execute(&mut debugger, "delete 12");
+        let output = execute(&mut debugger, "continue");
+        assert_eq!(output, "Program finished with exit code 7\n");
+        assert_eq!(debugger.vm().exit_code(), 7);
+        execute(&mut debugger, "break loop");
+        let output = execute(&mut debugger, "reverse");
+        assert!(output.starts_with("=> 0008: dec $0"));
+        assert_eq!(debugger.vm().exit_code(), 0);
+        assert_eq!(debugger.vm().registers()[0], 1);
+        assert!(!debugger.vm().equal_flag());
+        let output = execute(&mut debugger, "rc");
+        assert!(output.starts_with("=> 0008"));
+        assert_eq!(debugger.vm().registers()[0], 2);
+    }
+}
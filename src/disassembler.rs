@@ -0,0 +1,45 @@
+use crate::instruction::{DecodedInstruction, INSTRUCTION_LEN};
+
+/// Renders the instruction starting at `bytes[0]` in assembler syntax. Missing
+/// trailing bytes are read as zeroes.
+pub fn disassemble_instruction(bytes: &[u8]) -> String {
+    DecodedInstruction::decode(bytes).to_string()
+}
+
+/// Disassembles the whole program into `(offset, instruction)` pairs.
+pub fn disassemble(program: &[u8]) -> Vec<(usize, String)> {
+    program
+        .chunks(INSTRUCTION_LEN)
+        .enumerate()
+        .map(|(index, bytes)| {
+            (index * INSTRUCTION_LEN, disassemble_instruction(bytes))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::header::HEADER_LEN;
+
+    #[test]
+    fn test_disassemble_instruction() {
+        assert_eq!(disassemble_instruction(&[2, 3, 1, 244]), "load $3 #500");
+        assert_eq!(disassemble_instruction(&[3, 1, 2, 0]), "add $1 $2 $0");
+        assert_eq!(disassemble_instruction(&[17, 4, 0, 0]), "jne $4");
+        assert_eq!(disassemble_instruction(&[1, 0, 0, 0]), "halt");
+        assert_eq!(disassemble_instruction(&[200, 1, 2, 3]), "ill");
+    }
+
+    #[test]
+    fn test_disassemble_roundtrip() {
+        let source = "load $0 #100\nload $1 #2\ninc $1\neq $0 $1\nhalt #2\n";
+        let mut assembler = Assembler::default();
+        let bytecode = assembler.assemble(source).unwrap();
+        let listing = disassemble(&bytecode[HEADER_LEN..]);
+        let lines: Vec<_> = listing.iter().map(|(_, text)| text).collect();
+        assert_eq!(lines, source.lines().collect::<Vec<_>>());
+        assert_eq!(listing[4].0, 16);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::instruction::DecodedInstruction as I;
+
+/// Comparison setting the equal flag, shared by the fused forms of
+/// `eq`..`lte`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+}
+
+impl Comparison {
+    pub fn test(self, lhs: i64, rhs: i64) -> bool {
+        match self {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Gt => lhs > rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Gte => lhs >= rhs,
+            Comparison::Lte => lhs <= rhs,
+        }
+    }
+
+    /// Splits a comparison instruction into its kind and registers.
+    #[rustfmt::skip]
+    fn of(instr: I) -> Option<(Comparison, u8, u8)> {
+        let comparison = match instr {
+            I::Eq { a, b }  => (Comparison::Eq, a, b),
+            I::Ne { a, b }  => (Comparison::Ne, a, b),
+            I::Gt { a, b }  => (Comparison::Gt, a, b),
+            I::Lt { a, b }  => (Comparison::Lt, a, b),
+            I::Gte { a, b } => (Comparison::Gte, a, b),
+            I::Lte { a, b } => (Comparison::Lte, a, b),
+            _ => return None,
+        };
+        Some(comparison)
+    }
+}
+
+/// A pair of adjacent instructions executed with a single dispatch.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Superinstruction {
+    /// A comparison followed by `je` (`jump_if` set) or `jne`.
+    CompareJump {
+        comparison: Comparison,
+        a: u8,
+        b: u8,
+        jump_if: bool,
+        target: u8,
+    },
+    /// `inc` (`step` of 1) or `dec` (`step` of -1) followed by a comparison.
+    StepCompare {
+        reg: u8,
+        step: i64,
+        comparison: Comparison,
+        a: u8,
+        b: u8,
+    },
+}
+
+impl Superinstruction {
+    pub fn fuse(first: I, second: I) -> Option<Superinstruction> {
+        let fused = match (first, second) {
+            (compare, I::Je { target } | I::Jne { target }) => {
+                let (comparison, a, b) = Comparison::of(compare)?;
+                Superinstruction::CompareJump {
+                    comparison,
+                    a,
+                    b,
+                    jump_if: matches!(second, I::Je { .. }),
+                    target,
+                }
+            }
+            (I::Inc { reg } | I::Dec { reg }, compare) => {
+                let (comparison, a, b) = Comparison::of(compare)?;
+                let step = if matches!(first, I::Inc { .. }) {
+                    1
+                } else {
+                    -1
+                };
+                Superinstruction::StepCompare {
+                    reg,
+                    step,
+                    comparison,
+                    a,
+                    b,
+                }
+            }
+            _ => return None,
+        };
+        Some(fused)
+    }
+}
+
+/// Looks for fusible pairs in predecoded code. The result is indexed like
+/// `instrs`: a superinstruction stands in for the first instruction of its
+/// pair, while the second one keeps its slot for jumps that land on it.
+pub fn fuse(instrs: &[I]) -> Vec<Option<Superinstruction>> {
+    let pairs = instrs
+        .windows(2)
+        .map(|pair| Superinstruction::fuse(pair[0], pair[1]));
+    pairs.chain(instrs.last().map(|_| None)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::INSTRUCTION_LEN;
+    use crate::vm::Vm;
+    use crate::watchpoints::Watchpoint;
+
+    #[test]
+    fn test_fuse() {
+        let instrs = [
+            I::Inc { reg: 0 },
+            I::Lt { a: 0, b: 1 },
+            I::Jne { target: 2 },
+            I::Halt { code: 0 },
+        ];
+        assert_eq!(
+            fuse(&instrs),
+            [
+                Some(Superinstruction::StepCompare {
+                    reg: 0,
+                    step: 1,
+                    comparison: Comparison::Lt,
+                    a: 0,
+                    b: 1,
+                }),
+                Some(Superinstruction::CompareJump {
+                    comparison: Comparison::Lt,
+                    a: 0,
+                    b: 1,
+                    jump_if: false,
+                    target: 2,
+                }),
+                None,
+                None,
+            ]
+        );
+        assert!(fuse(&[]).is_empty());
+    }
+
+    /// Xorshift generator, the tests need reproducible programs only.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, bound: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % bound
+        }
+    }
+
+    /// Builds a program of fusible sequences mixed with other instructions.
+    /// Jumps only go forward, through `$6`, and never land past the `load`
+    /// that sets their target, so every program terminates.
+    fn random_program(rng: &mut Rng) -> Vec<u8> {
+        let len = 40 + rng.below(40) as usize;
+        let (mut instrs, mut jumps, mut skipped) = (vec![], vec![], vec![]);
+        while instrs.len() < len {
+            let (a, b) = (rng.below(6) as u8, rng.below(6) as u8);
+            let compare = match rng.below(6) {
+                0 => I::Eq { a, b },
+                1 => I::Ne { a, b },
+                2 => I::Gt { a, b },
+                3 => I::Lt { a, b },
+                4 => I::Gte { a, b },
+                _ => I::Lte { a, b },
+            };
+            match rng.below(6) {
+                0 | 1 => {
+                    jumps.push(instrs.len());
+                    skipped.extend([instrs.len() + 1, instrs.len() + 2]);
+                    instrs.push(I::Load { reg: 6, imm: 0 });
+                    instrs.push(compare);
+                    instrs.push(match rng.below(2) {
+                        0 => I::Je { target: 6 },
+                        _ => I::Jne { target: 6 },
+                    });
+                }
+                2 => instrs.extend([I::Inc { reg: a }, compare]),
+                3 => instrs.extend([I::Dec { reg: a }, compare]),
+                4 => instrs.push(I::Load {
+                    reg: a,
+                    imm: rng.below(4) as u16,
+                }),
+                _ => instrs.push(I::Sub { a, b, dst: a }),
+            }
+        }
+        instrs.push(I::Halt { code: 3 });
+        for index in jumps {
+            let targets: Vec<_> = (index + 3..=instrs.len())
+                .filter(|target| !skipped.contains(target))
+                .take(6)
+                .collect();
+            let target = targets[rng.below(targets.len() as u64) as usize];
+            let imm = (target * INSTRUCTION_LEN) as u16;
+            instrs[index] = I::Load { reg: 6, imm };
+        }
+        instrs.iter().flat_map(|instr| instr.encode()).collect()
+    }
+
+    /// Runs to completion, resuming after every watchpoint break.
+    fn run(vm: &mut Vm) -> String {
+        let mut stops = vec![];
+        loop {
+            let result = vm.run();
+            stops.push(format!("{result:?} at {}", vm.pc()));
+            if result.is_ok() {
+                break;
+            }
+        }
+        let hits: Vec<_> = vm.take_watch_hits();
+        format!(
+            "{stops:?} {:?} {} {} {:?} {hits:?}",
+            vm.registers(),
+            vm.equal_flag(),
+            vm.instr_pc(),
+            vm.state()
+        )
+    }
+
+    #[test]
+    fn test_fusion_matches_interpreter() {
+        // load $6 #24, load $7 #16, jump $7, eq $0 $1, jne $6, halt #1,
+        // halt #2: jumps onto the second half of a fused pair.
+        let into_pair = vec![
+            2, 6, 0, 24, 2, 7, 0, 16, 7, 7, 0, 0, 10, 0, 1, 0, 17, 6, 0, 0, 1,
+            0, 1, 0, 1, 0, 2, 0,
+        ];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut programs = vec![into_pair];
+        programs.extend((0..200).map(|_| random_program(&mut rng)));
+        for program in programs {
+            let watch = rng.below(6);
+            let build = || {
+                let mut vm =
+                    Vm::builder().program(program.clone()).build().unwrap();
+                let watchpoint = format!("${watch} > 1");
+                vm.add_watchpoint(watchpoint.parse::<Watchpoint>().unwrap())
+                    .unwrap();
+                vm
+            };
+            let mut plain = build();
+            let mut fused = build();
+            fused.enable_fusion();
+            assert_eq!(run(&mut fused), run(&mut plain));
+        }
+    }
+}
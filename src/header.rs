@@ -0,0 +1,156 @@
+use std::fmt;
+
+use crate::types::Width;
+
+pub const MAGIC: [u8; 4] = *b"RBVM";
+pub const VERSION: u8 = 1;
+/// The header is padded to a multiple of the instruction size, so it can be
+/// stripped without disturbing instruction alignment.
+pub const HEADER_LEN: usize = 8;
+/// Set in the flags byte when a debug info section follows the code.
+pub const FLAG_DEBUG_INFO: u8 = 1;
+
+/// The header, the code and the debug info section of a container.
+pub type Sections<'a> = (Header, &'a [u8], Option<&'a [u8]>);
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Truncated,
+    BadMagic,
+    UnsupportedVersion,
+    UnsupportedWidth,
+    InvalidSection,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "bytecode is shorter than a header"),
+            Error::BadMagic => write!(f, "bytecode doesn't start with RBVM"),
+            Error::UnsupportedVersion => {
+                write!(f, "unsupported bytecode version")
+            }
+            Error::UnsupportedWidth => write!(f, "unsupported register width"),
+            Error::InvalidSection => write!(f, "invalid debug info section"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Bytecode container header:
+/// ```text
+/// +-------------------------------------------------------+
+/// | Magic (4 bytes) | Version | Width (bits) | Flags | Pad |
+/// +-------------------------------------------------------+
+/// ```
+/// With [`FLAG_DEBUG_INFO`] set, the code is followed by a debug info section
+/// and the 32-bit big-endian length of that section.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Header {
+    pub version: u8,
+    pub width: Width,
+    pub debug_info: bool,
+}
+
+impl Header {
+    pub fn new(width: Width) -> Self {
+        Header {
+            version: VERSION,
+            width,
+            debug_info: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.width.bits());
+        bytes.push(match self.debug_info {
+            true => FLAG_DEBUG_INFO,
+            false => 0,
+        });
+        bytes.resize(HEADER_LEN, 0);
+        bytes
+    }
+
+    /// Parses the header and returns it with the code that follows it.
+    pub fn parse(bytes: &[u8]) -> Result<(Header, &[u8]), Error> {
+        let (header, code, _) = Header::parse_sections(bytes)?;
+        Ok((header, code))
+    }
+
+    /// Parses the header and returns it with the code and the debug info
+    /// section, if present.
+    pub fn parse_sections(bytes: &[u8]) -> Result<Sections<'_>, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::Truncated);
+        }
+        if bytes[..4] != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+        let width =
+            Width::try_from(bytes[5]).map_err(|_| Error::UnsupportedWidth)?;
+        let header = Header {
+            version: bytes[4],
+            width,
+            debug_info: bytes[6] & FLAG_DEBUG_INFO != 0,
+        };
+        let rest = &bytes[HEADER_LEN..];
+        if !header.debug_info {
+            return Ok((header, rest, None));
+        }
+        let (rest, len) =
+            rest.split_last_chunk::<4>().ok_or(Error::InvalidSection)?;
+        let code_len = rest
+            .len()
+            .checked_sub(u32::from_be_bytes(*len) as usize)
+            .ok_or(Error::InvalidSection)?;
+        let (code, section) = rest.split_at(code_len);
+        Ok((header, code, Some(section)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut bytes = Header::new(Width::W64).to_bytes();
+        assert_eq!(bytes.len(), HEADER_LEN);
+        bytes.extend([1, 0, 0, 0]);
+        let (header, code) = Header::parse(&bytes).unwrap();
+        assert_eq!(header, Header::new(Width::W64));
+        assert_eq!(code, &[1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_header_invalid() {
+        assert_eq!(Header::parse(&[1, 0, 0, 0]), Err(Error::Truncated));
+        let bytes = [0, 0, 0, 0, VERSION, 32, 0, 0];
+        assert_eq!(Header::parse(&bytes), Err(Error::BadMagic));
+        let mut bytes = Header::new(Width::W32).to_bytes();
+        bytes[5] = 16;
+        assert_eq!(Header::parse(&bytes), Err(Error::UnsupportedWidth));
+    }
+
+    #[test]
+    fn test_header_debug_info_section() {
+        let header = Header {
+            debug_info: true,
+            ..Header::new(Width::W32)
+        };
+        let mut bytes = header.to_bytes();
+        bytes.extend([1, 0, 0, 0, 7, 7, 0, 0, 0, 2]);
+        let (parsed, code, section) = Header::parse_sections(&bytes).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(code, &[1, 0, 0, 0]);
+        assert_eq!(section, Some(&[7, 7][..]));
+        bytes[HEADER_LEN + 9] = 20;
+        assert_eq!(Header::parse(&bytes), Err(Error::InvalidSection));
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+
+use self::DecodedInstruction as I;
+use crate::opcodes::Opcode;
+
+/// Encoded length of every instruction, see [`DecodedInstruction`].
+pub const INSTRUCTION_LEN: usize = 4;
+
+/// An instruction with its operands. Every instruction is encoded in four
+/// bytes, with 16-bit immediates stored big-endian:
+/// ```text
+/// +-----------------------------------------------+
+/// | Opcode | Register | Register | Register       |
+/// | Opcode | Register | 16-bit immediate          |
+/// | Opcode | 16-bit immediate    | Pad            |
+/// +-----------------------------------------------+
+/// ```
+/// Unused bytes are encoded as zeroes and ignored when decoding.
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[rustfmt::skip]
+pub enum DecodedInstruction {
+    Pad,
+    Halt { code: u16 },
+    Load { reg: u8, imm: u16 },
+    Add { a: u8, b: u8, dst: u8 },
+    Sub { a: u8, b: u8, dst: u8 },
+    Mul { a: u8, b: u8, dst: u8 },
+    Div { a: u8, b: u8, dst: u8 },
+    Jump { target: u8 },
+    Jf { offset: u8 },
+    Jb { offset: u8 },
+    Eq { a: u8, b: u8 },
+    Ne { a: u8, b: u8 },
+    Gt { a: u8, b: u8 },
+    Lt { a: u8, b: u8 },
+    Gte { a: u8, b: u8 },
+    Lte { a: u8, b: u8 },
+    Je { target: u8 },
+    Jne { target: u8 },
+    Inc { reg: u8 },
+    Dec { reg: u8 },
+    /// Calls the host handler, which can read and write any register.
+    Syscall { number: u16 },
+    /// A byte that isn't a known opcode.
+    Illegal { opcode: u8 },
+}
+
+impl DecodedInstruction {
+    /// Decodes the instruction starting at `bytes[0]`. Missing trailing bytes
+    /// are read as zeroes.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let byte = |index: usize| bytes.get(index).copied().unwrap_or_default();
+        let (a, b, dst) = (byte(1), byte(2), byte(3));
+        let imm =
+            |index: usize| u16::from_be_bytes([byte(index), byte(index + 1)]);
+        #[rustfmt::skip]
+        let decoded = match Opcode::from(byte(0)) {
+            Opcode::PAD     => I::Pad,
+            Opcode::HALT    => I::Halt { code: imm(1) },
+            Opcode::LOAD    => I::Load { reg: a, imm: imm(2) },
+            Opcode::ADD     => I::Add { a, b, dst },
+            Opcode::SUB     => I::Sub { a, b, dst },
+            Opcode::MUL     => I::Mul { a, b, dst },
+            Opcode::DIV     => I::Div { a, b, dst },
+            Opcode::JUMP    => I::Jump { target: a },
+            Opcode::JF      => I::Jf { offset: a },
+            Opcode::JB      => I::Jb { offset: a },
+            Opcode::EQ      => I::Eq { a, b },
+            Opcode::NE      => I::Ne { a, b },
+            Opcode::GT      => I::Gt { a, b },
+            Opcode::LT      => I::Lt { a, b },
+            Opcode::GTE     => I::Gte { a, b },
+            Opcode::LTE     => I::Lte { a, b },
+            Opcode::JE      => I::Je { target: a },
+            Opcode::JNE     => I::Jne { target: a },
+            Opcode::INC     => I::Inc { reg: a },
+            Opcode::DEC     => I::Dec { reg: a },
+            Opcode::SYSCALL => I::Syscall { number: imm(1) },
+            Opcode::ILL     => I::Illegal { opcode: byte(0) },
+        };
+        decoded
+    }
+
+    pub fn encode(&self) -> [u8; INSTRUCTION_LEN] {
+        let opcode = self.opcode() as u8;
+        let with_imm = |[high, low]: [u8; 2]| [opcode, high, low, 0];
+        match *self {
+            I::Pad => [opcode, 0, 0, 0],
+            I::Halt { code: imm } | I::Syscall { number: imm } => {
+                with_imm(imm.to_be_bytes())
+            }
+            I::Load { reg, imm } => {
+                let [high, low] = imm.to_be_bytes();
+                [opcode, reg, high, low]
+            }
+            I::Add { a, b, dst }
+            | I::Sub { a, b, dst }
+            | I::Mul { a, b, dst }
+            | I::Div { a, b, dst } => [opcode, a, b, dst],
+            I::Eq { a, b }
+            | I::Ne { a, b }
+            | I::Gt { a, b }
+            | I::Lt { a, b }
+            | I::Gte { a, b }
+            | I::Lte { a, b } => [opcode, a, b, 0],
+            I::Jump { target: reg }
+            | I::Jf { offset: reg }
+            | I::Jb { offset: reg }
+            | I::Je { target: reg }
+            | I::Jne { target: reg }
+            | I::Inc { reg }
+            | I::Dec { reg } => [opcode, reg, 0, 0],
+            I::Illegal { opcode } => [opcode, 0, 0, 0],
+        }
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        #[rustfmt::skip]
+        let opcode = match self {
+            I::Pad              => Opcode::PAD,
+            I::Halt { .. }      => Opcode::HALT,
+            I::Load { .. }      => Opcode::LOAD,
+            I::Add { .. }       => Opcode::ADD,
+            I::Sub { .. }       => Opcode::SUB,
+            I::Mul { .. }       => Opcode::MUL,
+            I::Div { .. }       => Opcode::DIV,
+            I::Jump { .. }      => Opcode::JUMP,
+            I::Jf { .. }        => Opcode::JF,
+            I::Jb { .. }        => Opcode::JB,
+            I::Eq { .. }        => Opcode::EQ,
+            I::Ne { .. }        => Opcode::NE,
+            I::Gt { .. }        => Opcode::GT,
+            I::Lt { .. }        => Opcode::LT,
+            I::Gte { .. }       => Opcode::GTE,
+            I::Lte { .. }       => Opcode::LTE,
+            I::Je { .. }        => Opcode::JE,
+            I::Jne { .. }       => Opcode::JNE,
+            I::Inc { .. }       => Opcode::INC,
+            I::Dec { .. }       => Opcode::DEC,
+            I::Syscall { .. }   => Opcode::SYSCALL,
+            I::Illegal { .. }   => Opcode::ILL,
+        };
+        opcode
+    }
+
+    /// Returns the registers named by the operands, split into the ones read
+    /// and the ones written.
+    #[rustfmt::skip]
+    pub fn registers(&self) -> (Vec<u8>, Vec<u8>) {
+        match *self {
+            I::Load { reg, .. }      => (vec![], vec![reg]),
+            I::Add { a, b, dst }
+            | I::Sub { a, b, dst }
+            | I::Mul { a, b, dst }
+            | I::Div { a, b, dst }   => (vec![a, b], vec![dst]),
+            I::Eq { a, b }
+            | I::Ne { a, b }
+            | I::Gt { a, b }
+            | I::Lt { a, b }
+            | I::Gte { a, b }
+            | I::Lte { a, b }        => (vec![a, b], vec![]),
+            I::Jump { target: reg }
+            | I::Jf { offset: reg }
+            | I::Jb { offset: reg }
+            | I::Je { target: reg }
+            | I::Jne { target: reg } => (vec![reg], vec![]),
+            I::Inc { reg }
+            | I::Dec { reg }         => (vec![reg], vec![reg]),
+            I::Pad
+            | I::Halt { .. }
+            | I::Syscall { .. }
+            | I::Illegal { .. }      => (vec![], vec![]),
+        }
+    }
+
+    /// Returns the registers the instruction may write, all of them for a
+    /// syscall.
+    pub fn writes(&self) -> Vec<u8> {
+        match self {
+            I::Syscall { .. } => (0..=u8::MAX).collect(),
+            _ => self.registers().1,
+        }
+    }
+}
+
+/// Renders the instruction in assembler syntax, e.g. `load $3 #500`. A zero
+/// exit code is left out of `halt`.
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.opcode())?;
+        match *self {
+            I::Pad | I::Illegal { .. } | I::Halt { code: 0 } => Ok(()),
+            I::Halt { code: imm } | I::Syscall { number: imm } => {
+                write!(f, " #{imm}")
+            }
+            I::Load { reg, imm } => write!(f, " ${reg} #{imm}"),
+            I::Add { a, b, dst }
+            | I::Sub { a, b, dst }
+            | I::Mul { a, b, dst }
+            | I::Div { a, b, dst } => write!(f, " ${a} ${b} ${dst}"),
+            I::Eq { a, b }
+            | I::Ne { a, b }
+            | I::Gt { a, b }
+            | I::Lt { a, b }
+            | I::Gte { a, b }
+            | I::Lte { a, b } => write!(f, " ${a} ${b}"),
+            I::Jump { target: reg }
+            | I::Jf { offset: reg }
+            | I::Jb { offset: reg }
+            | I::Je { target: reg }
+            | I::Jne { target: reg }
+            | I::Inc { reg }
+            | I::Dec { reg } => write!(f, " ${reg}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_encode_roundtrip() {
+        let instrs = [
+            I::Pad,
+            I::Halt { code: 7 },
+            I::Load { reg: 3, imm: 500 },
+            I::Div { a: 1, b: 2, dst: 3 },
+            I::Jump { target: 4 },
+            I::Lte { a: 5, b: 6 },
+            I::Dec { reg: 7 },
+            I::Syscall { number: 258 },
+            I::Illegal { opcode: 200 },
+        ];
+        for instr in instrs {
+            assert_eq!(DecodedInstruction::decode(&instr.encode()), instr);
+        }
+        assert_eq!(I::Load { reg: 3, imm: 500 }.encode(), [2, 3, 1, 244]);
+        assert_eq!(I::Syscall { number: 258 }.encode(), [20, 1, 2, 0]);
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        assert_eq!(
+            DecodedInstruction::decode(&[2, 1]),
+            I::Load { reg: 1, imm: 0 }
+        );
+        assert_eq!(DecodedInstruction::decode(&[]), I::Pad);
+    }
+}
@@ -0,0 +1,43 @@
+//! RBVM, a register-based virtual machine with an assembler for its
+//! bytecode.
+//!
+//! [`vm`], [`assembler`], [`instruction`], [`opcodes`] and [`types`] make up
+//! the stable API, along with the modules whose types they take or return:
+//! [`debug_info`], [`header`], [`profiler`], [`snapshot`], [`syscalls`],
+//! [`trace`] and [`watchpoints`].
+//! The other modules back the `rbvm` command line tools and may change
+//! between releases.
+//!
+//! ```
+//! use rbvm::assembler::Assembler;
+//! use rbvm::vm::Vm;
+//!
+//! let mut assembler = Assembler::default();
+//! let bytecode = assembler.assemble("load $0 #40\ninc $0\nhalt #2\n")?;
+//! let mut vm = Vm::default();
+//! vm.load_bytecode(&bytecode)?;
+//! assert_eq!(vm.run()?, 2);
+//! assert_eq!(vm.registers()[0], 41);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod assembler;
+pub mod bench;
+pub mod cfg;
+pub mod coverage;
+pub mod debug_info;
+pub mod debugger;
+pub mod disassembler;
+pub mod fusion;
+pub mod header;
+pub mod instruction;
+pub mod opcodes;
+pub mod profiler;
+pub mod repl;
+pub mod snapshot;
+pub mod syscalls;
+pub mod trace;
+pub mod types;
+pub mod verifier;
+pub mod vm;
+pub mod watchpoints;
@@ -1,16 +1,16 @@
-#![allow(incomplete_features)]
-#![feature(generic_const_exprs)]
-#![feature(stmt_expr_attributes)]
-
-use std::fs::read_to_string;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::{File, read_to_string};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
 
 use clap::{Parser, Subcommand};
-
-pub mod assembler;
-pub mod opcodes;
-pub mod repl;
-pub mod types;
-pub mod vm;
+use rbvm::{
+    assembler, bench, cfg, coverage, debug_info, debugger, header, instruction,
+    profiler, repl, snapshot, syscalls, trace, types, verifier, vm,
+    watchpoints,
+};
 
 const SUCCESS: i32 = 0;
 const ERROR: i32 = 1;
@@ -30,34 +30,445 @@ enum Commands {
         /// The file name to assemble
         #[arg(value_name = "FILE")]
         path: String,
+        #[command(flatten)]
+        vm_args: VmArgs,
+        /// Log writes to a register, e.g. `--watch '$1'` or
+        /// `--watch '$1 > 10'`
+        #[arg(long, value_name = "EXPR")]
+        watch: Vec<String>,
+        /// Write an execution trace to stderr, or to FILE with `--trace=FILE`
+        #[arg(
+            long,
+            value_name = "FILE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "-"
+        )]
+        trace: Option<String>,
+        /// Print an execution profile to stderr once the program stops
+        #[arg(long)]
+        profile: bool,
+        /// Read per-opcode cycle costs for the profile from `<mnemonic>
+        /// <cycles>` lines in FILE
+        #[arg(long, value_name = "FILE", requires = "profile")]
+        cost_table: Option<String>,
+        /// Print a per-line coverage report to stderr once the program stops
+        #[arg(long)]
+        coverage: bool,
+        /// Write an lcov coverage tracefile to FILE once the program stops
+        #[arg(long, value_name = "FILE")]
+        lcov: Option<String>,
+        /// Save the machine state to FILE once the program stops
+        #[arg(long, value_name = "FILE")]
+        save_snapshot: Option<String>,
+        /// Print the machine state to stderr once the program halts
+        #[arg(long)]
+        dump_state: bool,
+    },
+    /// Assemble a file into bytecode
+    Assemble {
+        /// The file name to assemble
+        #[arg(value_name = "FILE")]
+        path: String,
+        /// Where to write the bytecode, FILE with an `.rbc` extension by
+        /// default
+        #[arg(short, long, value_name = "OUTPUT")]
+        output: Option<String>,
+        /// Run the peephole optimiser
+        #[arg(short = 'O')]
+        optimize: bool,
+        /// Embed debug info mapping the code back to FILE
+        #[arg(long)]
+        debug_info: bool,
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Print the control-flow graph of a file in Graphviz DOT
+    Cfg {
+        /// The file name to assemble
+        #[arg(value_name = "FILE")]
+        path: String,
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Check bytecode for problems without running it
+    Verify {
+        /// The bytecode file to check
+        #[arg(value_name = "FILE")]
+        path: String,
+        #[command(flatten)]
+        target: TargetArgs,
+    },
+    /// Run a file repeatedly and report the interpreter speed
+    Bench {
+        /// The file name to assemble
+        #[arg(value_name = "FILE")]
+        path: String,
+        #[command(flatten)]
+        vm_args: VmArgs,
+        /// Number of timed runs
+        #[arg(
+            long,
+            default_value_t = 100,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        runs: u32,
+    },
+    /// Step through a file in the interactive debugger
+    Debug {
+        /// The file name to assemble
+        #[arg(value_name = "FILE")]
+        path: String,
+        #[command(flatten)]
+        vm_args: VmArgs,
     },
     /// Start the REPL
-    Repl,
+    Repl {
+        /// Restore the machine state saved in a snapshot FILE
+        #[arg(long, value_name = "FILE")]
+        snapshot: Option<String>,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct VmArgs {
+    #[command(flatten)]
+    target: TargetArgs,
+    /// Decode the program once up front instead of on every step
+    #[arg(long)]
+    predecode: bool,
+    /// Execute common instruction pairs as superinstructions, implies
+    /// `--predecode`
+    #[arg(long)]
+    fuse: bool,
+}
+
+#[derive(Debug, clap::Args)]
+struct TargetArgs {
+    /// Register word width in bits (32 or 64)
+    #[arg(long, default_value_t = 32)]
+    width: u8,
+    /// Number of registers available to the program
+    #[arg(
+        long,
+        default_value_t = vm::DEFAULT_REGISTERS as u16,
+        value_parser = clap::value_parser!(u16).range(1..=256)
+    )]
+    registers: u16,
+}
+
+fn exit_with_error(err: impl Into<Box<dyn Error>>) -> ! {
+    exit_with_error_at(None, err)
+}
+
+/// Prints the error along with its chain of sources and exits.
+fn exit_with_error_at(
+    location: Option<String>,
+    err: impl Into<Box<dyn Error>>,
+) -> ! {
+    let err = err.into();
+    match location {
+        Some(location) => eprintln!("An error ocurred at {location}: {err}"),
+        None => eprintln!("An error ocurred: {err}"),
+    }
+    let mut source = err.source();
+    while let Some(cause) = source {
+        eprintln!("  caused by: {cause}");
+        source = cause.source();
+    }
+    std::process::exit(ERROR);
+}
+
+impl TargetArgs {
+    fn config(&self) -> vm::Config {
+        let width = types::Width::try_from(self.width)
+            .unwrap_or_else(|err| exit_with_error(err));
+        vm::Config {
+            width,
+            registers: self.registers as usize,
+        }
+    }
+}
+
+/// Loads the file into a VM configured by `vm_args`, assembling it first
+/// unless it already holds bytecode. Bytecode comes with an empty assembler
+/// and the source file named in its debug info, if it embeds any.
+fn load(
+    path: &str,
+    vm_args: &VmArgs,
+) -> (String, assembler::Assembler, vm::Vm) {
+    let contents = std::fs::read(path).expect("File not found");
+    let config = vm_args.target.config();
+    let mut assembler = assembler::Assembler::new(config);
+    assembler.set_file(path);
+    let mut vm = vm::Vm::with_config(config);
+    vm.set_syscall_handler(syscalls::DefaultSyscalls::default());
+    if vm_args.fuse {
+        vm.enable_fusion();
+    } else if vm_args.predecode {
+        vm.enable_predecoding();
+    }
+    if contents.starts_with(&header::MAGIC) {
+        if let Err(err) = vm.load_bytecode(&contents) {
+            exit_with_error(err);
+        }
+        let source_code = vm
+            .debug_info()
+            .and_then(|info| read_to_string(&info.file).ok())
+            .unwrap_or_default();
+        return (source_code, assembler::Assembler::new(config), vm);
+    }
+    let source_code =
+        String::from_utf8(contents).unwrap_or_else(|err| exit_with_error(err));
+    let bytecode = assembler
+        .assemble(&source_code)
+        .unwrap_or_else(|err| exit_with_error(err));
+    if let Err(err) = vm.load_bytecode(&bytecode) {
+        exit_with_error(err);
+    }
+    (source_code, assembler, vm)
+}
+
+/// Builds the control-flow graph of bytecode, with the labels kept in its
+/// debug info.
+fn bytecode_cfg(bytecode: &[u8]) -> cfg::Cfg {
+    let (_, code, section) = header::Header::parse_sections(bytecode)
+        .unwrap_or_else(|err| exit_with_error(err));
+    let info = match section {
+        Some(section) => debug_info::DebugInfo::from_bytes(section)
+            .unwrap_or_else(|err| exit_with_error(err)),
+        None => debug_info::DebugInfo::default(),
+    };
+    let instrs = code
+        .chunks_exact(assembler::INSTRUCTION_LEN)
+        .map(instruction::DecodedInstruction::decode)
+        .collect();
+    let labels = info
+        .entries
+        .iter()
+        .filter_map(|entry| {
+            let label = entry.label.clone()?;
+            Some((label, entry.offset / assembler::INSTRUCTION_LEN))
+        })
+        .collect();
+    cfg::Cfg::from_instructions(instrs, &labels)
 }
 
 pub fn main() {
     let args = Args::parse();
 
     match args.command {
-        Commands::Run { path } => {
-            let source_code = read_to_string(path).expect("File not found");
-            let mut assembler = assembler::Assembler::default();
-            let mut vm = vm::Vm::default();
-            let bytecode = assembler.assemble(&source_code);
-            if let Ok(bytecode) = bytecode {
-                vm.push_bytes(bytecode);
+        Commands::Run {
+            path,
+            vm_args,
+            watch,
+            trace,
+            profile,
+            cost_table,
+            coverage,
+            lcov,
+            save_snapshot,
+            dump_state,
+        } => {
+            let (source_code, assembler, mut vm) = load(&path, &vm_args);
+            let debug_info = vm
+                .debug_info()
+                .unwrap_or_else(|| assembler.debug_info())
+                .clone();
+            let trace_sink = trace.map(|trace_path| {
+                let output: Box<dyn Write> = if trace_path == "-" {
+                    Box::new(io::stderr())
+                } else {
+                    let file = File::create(trace_path)
+                        .unwrap_or_else(|err| exit_with_error(err));
+                    Box::new(BufWriter::new(file))
+                };
+                Rc::new(RefCell::new(trace::TextTrace::with_debug_info(
+                    output,
+                    debug_info.clone(),
+                )))
+            });
+            if let Some(trace_sink) = &trace_sink {
+                vm.set_trace_sink(trace_sink.clone());
+            }
+            for expression in watch {
+                let mut watchpoint = expression
+                    .parse::<watchpoints::Watchpoint>()
+                    .unwrap_or_else(|err| exit_with_error(err));
+                watchpoint.action = watchpoints::Action::Log;
+                if let Err(err) = vm.add_watchpoint(watchpoint) {
+                    exit_with_error(err);
+                }
+            }
+            let hits =
+                Rc::new(RefCell::new(watchpoints::TextHits::with_debug_info(
+                    io::stderr(),
+                    debug_info.clone(),
+                )));
+            vm.set_watch_sink(hits.clone());
+            let costs = match cost_table {
+                Some(cost_path) => {
+                    let text = read_to_string(cost_path)
+                        .unwrap_or_else(|err| exit_with_error(err));
+                    profiler::CostTable::parse(&text)
+                        .unwrap_or_else(|err| exit_with_error(err))
+                }
+                None => profiler::CostTable::default(),
+            };
+            if profile || coverage || lcov.is_some() {
+                vm.enable_profiling();
+            }
+            let result = vm.run();
+            if profile && let Some(profile) = vm.profile() {
+                let report = profiler::Report {
+                    profile,
+                    program: vm.program(),
+                    costs: &costs,
+                    source_line: |pc| debug_info.line(pc),
+                };
+                eprintln!("{report}");
             }
-            if let Err(err) = vm.run() {
-                eprintln!("An error ocurred: {err:?}");
+            if let Some(profile) = vm.profile()
+                && (coverage || lcov.is_some())
+            {
+                let report =
+                    coverage::Coverage::new(profile, vm.program(), |pc| {
+                        debug_info.line(pc)
+                    });
+                if coverage {
+                    eprint!("{}", report.to_text(&source_code));
+                }
+                if let Some(lcov_path) = lcov
+                    && let Err(err) =
+                        std::fs::write(lcov_path, report.to_lcov(&path))
+                {
+                    exit_with_error(err);
+                }
+            }
+            if dump_state && result.is_ok() {
+                eprintln!("VM state: {vm:#?}");
+            }
+            if let Some(snapshot_path) = save_snapshot
+                && let Err(err) = vm.snapshot().save(snapshot_path)
+            {
+                exit_with_error(err);
+            }
+            let location = debug_info
+                .location(vm.instr_pc())
+                .map(|location| location.to_string());
+            let dropped = hits.borrow().dropped()
+                + trace_sink
+                    .as_ref()
+                    .map_or(0, |sink| sink.borrow().dropped());
+            if dropped > 0 {
+                eprintln!("{dropped} trace or watch lines couldn't be written");
+            }
+            // `process::exit` skips destructors, dropping the VM and the sink
+            // flushes the trace.
+            drop(vm);
+            drop(trace_sink);
+            let exit_code =
+                result.unwrap_or_else(|err| exit_with_error_at(location, err));
+            std::process::exit(exit_code);
+        }
+        Commands::Assemble {
+            path,
+            output,
+            optimize,
+            debug_info,
+            target,
+        } => {
+            let source_code = read_to_string(&path).expect("File not found");
+            let mut assembler = assembler::Assembler::new(target.config());
+            assembler.set_file(&path);
+            assembler.set_optimize(optimize);
+            assembler.set_embed_debug_info(debug_info);
+            let bytecode = assembler
+                .assemble(&source_code)
+                .unwrap_or_else(|err| exit_with_error(err));
+            let output = output.unwrap_or_else(|| {
+                Path::new(&path).with_extension("rbc").display().to_string()
+            });
+            if let Err(err) = std::fs::write(output, bytecode) {
+                exit_with_error(err);
+            }
+        }
+        Commands::Cfg { path, target } => {
+            let contents = std::fs::read(&path).expect("File not found");
+            let graph = if contents.starts_with(&header::MAGIC) {
+                bytecode_cfg(&contents)
+            } else {
+                let source_code = String::from_utf8(contents)
+                    .unwrap_or_else(|err| exit_with_error(err));
+                let mut assembler = assembler::Assembler::new(target.config());
+                assembler.set_file(&path);
+                if let Err(err) = assembler.assemble(&source_code) {
+                    exit_with_error(err);
+                }
+                cfg::Cfg::new(assembler.program())
+                    .unwrap_or_else(|err| exit_with_error(err))
+            };
+            print!("{}", graph.to_dot());
+        }
+        Commands::Verify { path, target } => {
+            let bytecode = std::fs::read(&path).expect("File not found");
+            let findings =
+                verifier::verify_bytecode(&bytecode, target.config())
+                    .unwrap_or_else(|err| exit_with_error(err));
+            let info = header::Header::parse_sections(&bytecode)
+                .ok()
+                .and_then(|(_, _, section)| section)
+                .and_then(|section| {
+                    debug_info::DebugInfo::from_bytes(section).ok()
+                });
+            for finding in &findings {
+                let location =
+                    info.as_ref().and_then(|info| info.location(finding.pc));
+                match location {
+                    Some(location) => println!("{finding} ({location})"),
+                    None => println!("{finding}"),
+                }
+            }
+            if findings.iter().any(|finding| !finding.is_warning()) {
                 std::process::exit(ERROR);
             }
-            println!("VM state: {vm:#?}");
         }
-        Commands::Repl => {
-            let mut repl = repl::Repl::default();
+        Commands::Bench {
+            path,
+            vm_args,
+            runs,
+        } => {
+            let (_, _, mut vm) = load(&path, &vm_args);
+            // Syscalls see no input and their output is dropped, so it
+            // doesn't repeat once per run around the report.
+            vm.set_syscall_handler(syscalls::DefaultSyscalls::new(
+                Box::new(io::empty()),
+                Box::new(io::sink()),
+            ));
+            let measurement = bench::measure(&mut vm, runs)
+                .unwrap_or_else(|err| exit_with_error(err));
+            println!("{measurement}");
+        }
+        Commands::Debug { path, vm_args } => {
+            let (source_code, assembler, vm) = load(&path, &vm_args);
+            let mut debugger =
+                debugger::Debugger::new(vm, assembler, &source_code);
+            if let Err(err) = debugger.run() {
+                exit_with_error(err);
+            }
+        }
+        Commands::Repl { snapshot } => {
+            let mut vm = vm::Vm::default();
+            if let Some(snapshot_path) = snapshot {
+                let snapshot = snapshot::Snapshot::load(snapshot_path)
+                    .unwrap_or_else(|err| exit_with_error(err));
+                if let Err(err) = vm.restore(snapshot) {
+                    exit_with_error(err);
+                }
+            }
+            let mut repl = repl::Repl::new(vm);
             if let Err(err) = repl.run() {
-                eprintln!("An error ocurred: {err:?}");
-                std::process::exit(ERROR);
+                exit_with_error(err);
             }
         }
     }
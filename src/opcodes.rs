@@ -20,12 +20,13 @@ pub enum Opcode {
     JNE,
     INC,
     DEC,
+    SYSCALL,
     ILL,
 }
 
 impl From<u8> for Opcode {
+    #[rustfmt::skip]
     fn from(value: u8) -> Self {
-        #[rustfmt::skip]
         match value {
             0   => Opcode::PAD,
             1   => Opcode::HALT,
@@ -47,14 +48,15 @@ impl From<u8> for Opcode {
             17  => Opcode::JNE,
             18  => Opcode::INC,
             19  => Opcode::DEC,
+            20  => Opcode::SYSCALL,
             _   => Opcode::ILL,
         }
     }
 }
 
 impl From<&str> for Opcode {
+    #[rustfmt::skip]
     fn from(value: &str) -> Self {
-        #[rustfmt::skip]
         match value {
             "pad"   => Opcode::PAD,
             "halt"  => Opcode::HALT,
@@ -76,11 +78,43 @@ impl From<&str> for Opcode {
             "jne"   => Opcode::JNE,
             "inc"   => Opcode::INC,
             "dec"   => Opcode::DEC,
+            "syscall" => Opcode::SYSCALL,
             _       => Opcode::ILL,
         }
     }
 }
 
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        #[rustfmt::skip]
+        let mnemonic = match self {
+            Opcode::PAD     => "pad",
+            Opcode::HALT    => "halt",
+            Opcode::LOAD    => "load",
+            Opcode::ADD     => "add",
+            Opcode::SUB     => "sub",
+            Opcode::MUL     => "mul",
+            Opcode::DIV     => "div",
+            Opcode::JUMP    => "jump",
+            Opcode::JF      => "jf",
+            Opcode::JB      => "jb",
+            Opcode::EQ      => "eq",
+            Opcode::NE      => "ne",
+            Opcode::GT      => "gt",
+            Opcode::LT      => "lt",
+            Opcode::GTE     => "gte",
+            Opcode::LTE     => "lte",
+            Opcode::JE      => "je",
+            Opcode::JNE     => "jne",
+            Opcode::INC     => "inc",
+            Opcode::DEC     => "dec",
+            Opcode::SYSCALL => "syscall",
+            Opcode::ILL     => "ill",
+        };
+        write!(f, "{mnemonic}")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,4 +132,10 @@ mod tests {
         let opcode = Opcode::from("illegal");
         assert_eq!(opcode, Opcode::ILL);
     }
+
+    #[test]
+    fn test_opcode_display() {
+        assert_eq!(Opcode::SYSCALL.to_string(), "syscall");
+        assert_eq!(Opcode::from(Opcode::JNE.to_string().as_str()), Opcode::JNE);
+    }
 }
@@ -0,0 +1,301 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cfg::Cfg;
+use crate::disassembler::disassemble_instruction;
+use crate::instruction::{DecodedInstruction, INSTRUCTION_LEN};
+use crate::opcodes::Opcode;
+
+/// Entries shown in the hottest-first sections of the report.
+const REPORT_TOP: usize = 10;
+
+/// Execution counters collected by [`crate::vm::Vm::enable_profiling`].
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Profile {
+    /// Executions per instruction, indexed by `pc / INSTRUCTION_LEN`.
+    pub counts: Vec<u64>,
+    /// Non-sequential transitions `(from, to)` and the number of times they
+    /// were taken.
+    pub edges: BTreeMap<(usize, usize), u64>,
+}
+
+impl Profile {
+    /// Records the execution of the instruction at `from` which left the pc
+    /// at `to`.
+    pub fn record(&mut self, from: usize, to: usize) {
+        let index = from / INSTRUCTION_LEN;
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        if to != from + INSTRUCTION_LEN {
+            *self.edges.entry((from, to)).or_default() += 1;
+        }
+    }
+
+    pub fn count(&self, pc: usize) -> u64 {
+        let index = pc / INSTRUCTION_LEN;
+        self.counts.get(index).copied().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+/// Estimated cycles per opcode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    costs: Vec<u64>,
+}
+
+impl Default for CostTable {
+    /// Every instruction costs one cycle, except for multiplication,
+    /// division and syscalls.
+    fn default() -> Self {
+        let mut table = CostTable {
+            costs: vec![1; Opcode::ILL as usize + 1],
+        };
+        table.set(Opcode::MUL, 3);
+        table.set(Opcode::DIV, 10);
+        table.set(Opcode::SYSCALL, 20);
+        table
+    }
+}
+
+impl CostTable {
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[opcode as usize]
+    }
+
+    pub fn set(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+
+    /// Parses `<mnemonic> <cycles>` lines overriding the default costs.
+    /// Blank lines are skipped.
+    pub fn parse(text: &str) -> Result<CostTable, &'static str> {
+        let mut table = CostTable::default();
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let mut words = line.split_whitespace();
+            let (Some(mnemonic), Some(cost), None) =
+                (words.next(), words.next(), words.next())
+            else {
+                return Err("Expected `<mnemonic> <cycles>`");
+            };
+            let opcode = match Opcode::from(mnemonic) {
+                Opcode::ILL if mnemonic != "ill" => {
+                    return Err("Unknown mnemonic");
+                }
+                opcode => opcode,
+            };
+            table.set(opcode, cost.parse().map_err(|_| "Invalid cost")?);
+        }
+        Ok(table)
+    }
+}
+
+/// Text report of a profile of `program`. `source_line` maps code offsets to
+/// source lines when assembly metadata is available.
+pub struct Report<'a, F> {
+    pub profile: &'a Profile,
+    pub program: &'a [u8],
+    pub costs: &'a CostTable,
+    pub source_line: F,
+}
+
+impl<F: Fn(usize) -> Option<usize>> Report<'_, F> {
+    fn at(&self, pc: usize) -> String {
+        match (self.source_line)(pc) {
+            Some(line) => format!("{pc:04} (line {line})"),
+            None => format!("{pc:04}"),
+        }
+    }
+
+    fn opcode_at(&self, pc: usize) -> Opcode {
+        DecodedInstruction::decode(&self.program[pc..]).opcode()
+    }
+
+    fn cycles_at(&self, pc: usize) -> u64 {
+        self.profile.count(pc) * self.costs.cost(self.opcode_at(pc))
+    }
+}
+
+impl<F: Fn(usize) -> Option<usize>> fmt::Display for Report<'_, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (profile, program) = (self.profile, self.program);
+        let executed: Vec<usize> = (0..program.len())
+            .step_by(INSTRUCTION_LEN)
+            .filter(|pc| profile.count(*pc) > 0)
+            .collect();
+        let total_cycles: u64 =
+            executed.iter().map(|pc| self.cycles_at(*pc)).sum();
+        writeln!(f, "Executed instructions: {}", profile.total())?;
+        writeln!(f, "Estimated cycles: {total_cycles}")?;
+
+        let mut opcodes = BTreeMap::<String, (u64, u64)>::new();
+        for pc in &executed {
+            let opcode = self.opcode_at(*pc).to_string();
+            let entry = opcodes.entry(opcode).or_default();
+            entry.0 += profile.count(*pc);
+            entry.1 += self.cycles_at(*pc);
+        }
+        let mut opcodes: Vec<_> = opcodes.into_iter().collect();
+        opcodes.sort_by_key(|(_, (_, cycles))| Reverse(*cycles));
+        writeln!(f, "\nOpcodes:\n{:>10} {:>10}  opcode", "count", "cycles")?;
+        for (opcode, (count, cycles)) in opcodes {
+            writeln!(f, "{count:>10} {cycles:>10}  {opcode}")?;
+        }
+
+        writeln!(
+            f,
+            "\nInstructions:\n{:>10} {:>10}  address",
+            "count", "cycles"
+        )?;
+        for pc in &executed {
+            let instr = disassemble_instruction(&program[*pc..]);
+            let (count, cycles) = (profile.count(*pc), self.cycles_at(*pc));
+            writeln!(f, "{count:>10} {cycles:>10}  {}: {instr}", self.at(*pc))?;
+        }
+
+        let mut blocks: Vec<_> = basic_blocks(profile, program)
+            .into_iter()
+            .filter(|(start, _)| profile.count(*start) > 0)
+            .map(|(start, end)| {
+                let cycles: u64 = (start..end)
+                    .step_by(INSTRUCTION_LEN)
+                    .map(|pc| self.cycles_at(pc))
+                    .sum();
+                (start, end, cycles)
+            })
+            .collect();
+        blocks.sort_by_key(|(.., cycles)| Reverse(*cycles));
+        writeln!(
+            f,
+            "\nHottest basic blocks:\n{:>10} {:>10}  block",
+            "count", "cycles"
+        )?;
+        for (start, end, cycles) in blocks.into_iter().take(REPORT_TOP) {
+            let count = profile.count(start);
+            let (first, last) =
+                (self.at(start), self.at(end - INSTRUCTION_LEN));
+            writeln!(f, "{count:>10} {cycles:>10}  {first} - {last}")?;
+        }
+
+        // A transition to the same or an earlier address closes a loop.
+        let mut loops: Vec<_> = profile
+            .edges
+            .iter()
+            .filter(|((from, to), _)| to <= from)
+            .collect();
+        loops.sort_by_key(|(_, count)| Reverse(**count));
+        writeln!(
+            f,
+            "\nHottest loops:\n{:>10}  header <- back edge",
+            "iterations"
+        )?;
+        for ((from, to), count) in loops.into_iter().take(REPORT_TOP) {
+            writeln!(f, "{count:>10}  {} <- {}", self.at(*to), self.at(*from))?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the program into `(start, end)` ranges of straight-line code, the
+/// blocks of its [`Cfg`]. Observed jump targets are passed to it as labels, so
+/// jumps it can't resolve still split blocks where they landed.
+fn basic_blocks(profile: &Profile, program: &[u8]) -> Vec<(usize, usize)> {
+    let instrs: Vec<_> = program
+        .chunks_exact(INSTRUCTION_LEN)
+        .map(DecodedInstruction::decode)
+        .collect();
+    let targets: BTreeMap<String, usize> = profile
+        .edges
+        .keys()
+        .filter(|(_, to)| to % INSTRUCTION_LEN == 0)
+        .map(|(_, to)| (to.to_string(), to / INSTRUCTION_LEN))
+        .collect();
+    Cfg::from_instructions(instrs, &targets)
+        .blocks
+        .iter()
+        .map(|block| (block.start, block.end))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::vm::Vm;
+
+    fn get_test_profile(source_code: &str) -> (Vm, Assembler) {
+        let mut assembler = Assembler::default();
+        let bytecode = assembler.assemble(source_code).unwrap();
+        let mut vm = Vm::default();
+        vm.load_bytecode(&bytecode).unwrap();
+        vm.enable_profiling();
+        assert!(vm.run().is_ok());
+        (vm, assembler)
+    }
+
+    const PROGRAM: &str = "load $0 #3
+load $1 @loop
+loop: dec $0
+mul $0 $0 $2
+eq $0 $3
+jne $1
+halt
+";
+
+    #[test]
+    fn test_profile_counts() {
+        let (vm, _) = get_test_profile(PROGRAM);
+        let profile = vm.profile().unwrap();
+        assert_eq!(profile.total(), 15);
+        assert_eq!(profile.count(0), 1);
+        assert_eq!(profile.count(8), 3);
+        assert_eq!(profile.count(24), 1);
+        assert_eq!(profile.edges, BTreeMap::from([((20, 8), 2)]));
+        let blocks = basic_blocks(profile, vm.program());
+        assert_eq!(blocks, vec![(0, 8), (8, 24), (24, 28)]);
+    }
+
+    #[test]
+    fn test_report() {
+        let (vm, assembler) = get_test_profile(PROGRAM);
+        let report = Report {
+            profile: vm.profile().unwrap(),
+            program: vm.program(),
+            costs: &CostTable::default(),
+            source_line: |pc| assembler.source_line(pc),
+        }
+        .to_string();
+        assert!(
+            report.starts_with(
+                "Executed instructions: 15\nEstimated cycles: 21\n"
+            )
+        );
+        assert!(report.contains("         3          9  mul\n"));
+        assert!(
+            report.contains("         3          3  0016 (line 5): eq $0 $3\n")
+        );
+        assert!(report.contains(
+            "         3         18  0008 (line 3) - 0020 (line 6)\n"
+        ));
+        assert!(
+            report.contains("         2  0008 (line 3) <- 0020 (line 6)\n")
+        );
+    }
+
+    #[test]
+    fn test_cost_table() {
+        let table = CostTable::parse("div 40\n\nload 2\n").unwrap();
+        assert_eq!(table.cost(Opcode::DIV), 40);
+        assert_eq!(table.cost(Opcode::LOAD), 2);
+        assert_eq!(table.cost(Opcode::MUL), 3);
+        assert!(CostTable::parse("load").is_err());
+        assert!(CostTable::parse("nope 1").is_err());
+        assert!(CostTable::parse("load x").is_err());
+    }
+}
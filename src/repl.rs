@@ -2,7 +2,7 @@ use std::io;
 use std::io::Write;
 
 use crate::assembler::program_parser::*;
-use crate::vm::{Error, Vm};
+use crate::vm::{Error, State, Vm};
 
 #[allow(dead_code)]
 #[derive(Default, Debug)]
@@ -12,6 +12,14 @@ pub struct Repl {
 }
 
 impl Repl {
+    /// Starts the REPL on an existing VM, e.g. one restored from a snapshot.
+    pub fn new(vm: Vm) -> Self {
+        Repl {
+            vm,
+            command_buffer: vec![],
+        }
+    }
+
     pub fn run(&mut self) -> Result<Vm, Error> {
         println!("RBVM (0.1.0) REPL");
         loop {
@@ -28,7 +36,11 @@ impl Repl {
             match buffer {
                 "!exit" => return Ok(self.vm.clone()),
                 "!buffer" => self.print_command_buffer(),
-                "!registers" => println!("{:?}", self.vm.registers),
+                "!registers" => println!("{:?}", self.vm.registers()),
+                "!reset" => self.reset(),
+                command if command.starts_with("!save ") => {
+                    self.save_snapshot(command["!save ".len()..].trim())
+                }
                 _ => self.process_line(buffer),
             }
         }
@@ -41,7 +53,27 @@ impl Repl {
         }
     }
 
+    fn save_snapshot(&self, path: &str) {
+        match self.vm.snapshot().save(path) {
+            Ok(()) => println!("Snapshot saved to {path}"),
+            Err(err) => eprintln!("Unable to save snapshot: {err}"),
+        }
+    }
+
+    /// Starts over with cleared registers and no code. Attachments such as
+    /// the syscall handler are kept.
+    fn reset(&mut self) {
+        let empty = Vm::with_config(self.vm.config());
+        self.vm
+            .restore(empty.snapshot())
+            .expect("an empty VM has no registers to check");
+    }
+
     fn process_line(&mut self, buffer: &str) {
+        if self.vm.state() == State::Halted {
+            eprintln!("The program has halted, enter !reset to start over");
+            return;
+        }
         let parsed_program = program_parser(buffer);
         if let Err(_err) = &parsed_program {
             eprintln!("Instruction not parsed. Resuming...");
@@ -50,12 +82,46 @@ impl Repl {
         let (_, result) = parsed_program.unwrap();
         let bytecode = result.to_bytes();
         if let Ok(bytecode) = bytecode {
-            for byte in bytecode {
-                self.vm.push_byte(byte);
+            if let Err(err) = self.vm.push_bytes(bytecode) {
+                eprintln!("Error ocurred while processing line: {err}");
+                return;
             }
             if let Err(err) = self.vm.run_once() {
-                eprintln!("Error ocurred while processing line: {err:?}");
+                eprintln!("Error ocurred while processing line: {err}");
+                // Later lines still run after a fault, but not after a halt.
+                let _ = self.vm.resume();
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_after_halt() {
+        let mut repl = Repl::default();
+        repl.process_line("load $0 #5");
+        repl.process_line("halt #2");
+        assert_eq!(repl.vm.state(), State::Halted);
+        repl.process_line("inc $0");
+        assert_eq!(repl.vm.registers()[0], 5);
+        assert_eq!(repl.vm.program().len(), 8);
+
+        repl.reset();
+        assert_eq!(repl.vm.state(), State::Executing);
+        repl.process_line("inc $0");
+        assert_eq!(repl.vm.registers()[0], 1);
+        assert_eq!(repl.vm.program().len(), 4);
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut repl = Repl::default();
+        repl.process_line("load $12 #1");
+        assert!(repl.vm.program().is_empty());
+        repl.process_line("load $7 #1");
+        assert_eq!(repl.vm.registers()[7], 1);
+    }
+}
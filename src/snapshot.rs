@@ -0,0 +1,209 @@
+use std::path::Path;
+use std::{fmt, fs, io};
+
+use crate::types::Width;
+use crate::vm::{Config, MAX_REGISTERS, State};
+
+pub const MAGIC: [u8; 4] = *b"RBVS";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Truncated,
+    BadMagic,
+    UnsupportedVersion,
+    InvalidField,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(_) => write!(f, "could not access the snapshot file"),
+            Error::Truncated => write!(f, "snapshot is truncated"),
+            Error::BadMagic => write!(f, "snapshot doesn't start with RBVS"),
+            Error::UnsupportedVersion => {
+                write!(f, "unsupported snapshot version")
+            }
+            Error::InvalidField => write!(f, "snapshot has an invalid field"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Machine state of a [`crate::vm::Vm`]. Host attachments such as the syscall
+/// handler, trace sinks and watchpoints are not part of it.
+///
+/// Snapshots are stored as big-endian fields:
+/// ```text
+/// +--------------------------------------------------------------------+
+/// | Magic (4 bytes) | Version | Width (bits) | State | Equal flag      |
+/// | Register count (u16) | Exit code (i32) | pc (u64) | Remainder (i64) |
+/// | Registers (i64 each) | Program length (u64) | Program              |
+/// +--------------------------------------------------------------------+
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub config: Config,
+    pub registers: Vec<i64>,
+    pub pc: usize,
+    pub program: Vec<u8>,
+    pub remainder: i64,
+    pub equal_flag: bool,
+    pub state: State,
+    pub exit_code: i32,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.config.width.bits());
+        bytes.push(self.state as u8);
+        bytes.push(self.equal_flag as u8);
+        bytes.extend((self.registers.len() as u16).to_be_bytes());
+        bytes.extend(self.exit_code.to_be_bytes());
+        bytes.extend((self.pc as u64).to_be_bytes());
+        bytes.extend(self.remainder.to_be_bytes());
+        for register in &self.registers {
+            bytes.extend(register.to_be_bytes());
+        }
+        bytes.extend((self.program.len() as u64).to_be_bytes());
+        bytes.extend(&self.program);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, Error> {
+        let mut reader = Reader { bytes };
+        if reader.take::<4>()? != MAGIC {
+            return Err(Error::BadMagic);
+        }
+        if reader.take::<1>()?[0] != VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+        let width = Width::try_from(reader.take::<1>()?[0])
+            .map_err(|_| Error::InvalidField)?;
+        let state = State::try_from(reader.take::<1>()?[0])
+            .map_err(|_| Error::InvalidField)?;
+        let equal_flag = match reader.take::<1>()?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::InvalidField),
+        };
+        let register_count = u16::from_be_bytes(reader.take()?) as usize;
+        if !(1..=MAX_REGISTERS).contains(&register_count) {
+            return Err(Error::InvalidField);
+        }
+        let exit_code = i32::from_be_bytes(reader.take()?);
+        let pc = u64::from_be_bytes(reader.take()?) as usize;
+        let remainder = i64::from_be_bytes(reader.take()?);
+        let mut registers = Vec::with_capacity(register_count);
+        for _ in 0..register_count {
+            registers.push(i64::from_be_bytes(reader.take()?));
+        }
+        let program_len = u64::from_be_bytes(reader.take()?) as usize;
+        if reader.bytes.len() != program_len {
+            return Err(Error::Truncated);
+        }
+        Ok(Snapshot {
+            config: Config {
+                width,
+                registers: register_count,
+            },
+            registers,
+            pc,
+            program: reader.bytes.to_vec(),
+            remainder,
+            equal_flag,
+            state,
+            exit_code,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        fs::write(path, self.to_bytes()).map_err(Error::Io)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Snapshot, Error> {
+        Snapshot::from_bytes(&fs::read(path).map_err(Error::Io)?)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let (taken, rest) = self
+            .bytes
+            .split_first_chunk::<N>()
+            .ok_or(Error::Truncated)?;
+        self.bytes = rest;
+        Ok(*taken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{self, Vm};
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        // load $1 #300, inc $1, eq $1 $1, halt
+        let program = vec![2, 1, 1, 44, 18, 1, 0, 0, 10, 1, 1, 0, 1, 0, 0, 0];
+        let mut test_vm = Vm::builder()
+            .program(program)
+            .remainder(-4)
+            .build()
+            .unwrap();
+        assert!(test_vm.run_once().is_ok());
+
+        let snapshot = test_vm.snapshot();
+        let bytes = snapshot.to_bytes();
+        let restored = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(restored, snapshot);
+
+        let mut restored_vm = Vm::default();
+        restored_vm.restore(restored).unwrap();
+        assert_eq!(restored_vm.pc(), 4);
+        assert_eq!(restored_vm.registers()[1], 300);
+        assert_eq!(restored_vm.remainder(), -4);
+        assert!(restored_vm.run().is_ok());
+        assert!(test_vm.run().is_ok());
+        assert_eq!(restored_vm.snapshot(), test_vm.snapshot());
+    }
+
+    #[test]
+    fn test_snapshot_invalid() {
+        let bytes = Vm::default().snapshot().to_bytes();
+        let result = Snapshot::from_bytes(&bytes[..bytes.len() - 1]);
+        assert!(matches!(result, Err(Error::Truncated)));
+        let result = Snapshot::from_bytes(&bytes[1..]);
+        assert!(matches!(result, Err(Error::BadMagic)));
+        let mut invalid = bytes.clone();
+        invalid[6] = 42;
+        let result = Snapshot::from_bytes(&invalid);
+        assert!(matches!(result, Err(Error::InvalidField)));
+
+        // The snapshot parses, but `load $12 #1` needs more registers.
+        let mut snapshot = Vm::default().snapshot();
+        snapshot.program = vec![2, 12, 0, 1];
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        let mut test_vm = Vm::default();
+        assert!(matches!(
+            test_vm.restore(snapshot),
+            Err(vm::Error::RegisterOutOfRange(12))
+        ));
+        assert!(test_vm.program().is_empty());
+    }
+}
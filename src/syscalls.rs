@@ -0,0 +1,223 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+use crate::vm::Vm;
+
+pub const EXIT: u16 = 0;
+pub const PRINT_INT: u16 = 1;
+pub const PRINT_CHAR: u16 = 2;
+pub const READ_INT: u16 = 3;
+pub const READ_CHAR: u16 = 4;
+
+/// Register used to pass the syscall argument and to return its result.
+pub const ARG_REGISTER: u8 = 0;
+
+#[derive(Debug)]
+pub enum Trap {
+    Exit(i32),
+    UnknownSyscall(u16),
+    InvalidArgument,
+    Io(io::Error),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::Exit(code) => write!(f, "exit with code {code}"),
+            Trap::UnknownSyscall(number) => {
+                write!(f, "unknown syscall {number}")
+            }
+            Trap::InvalidArgument => write!(f, "invalid syscall argument"),
+            Trap::Io(_) => write!(f, "syscall I/O failed"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Trap::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Host side of the `syscall #n` instruction. The handler gets full access to
+/// the machine, so it can read its arguments from registers and write results
+/// back.
+pub trait SyscallHandler: std::fmt::Debug {
+    fn call(&mut self, number: u16, vm: &mut Vm) -> Result<(), Trap>;
+}
+
+/// Handlers for the syscalls numbered by the constants of this module. All of
+/// them take their argument from and return their result into `$0`. The
+/// streams default to the process stdin and stdout.
+pub struct DefaultSyscalls {
+    input: BufReader<Box<dyn Read>>,
+    output: Box<dyn Write>,
+}
+
+impl DefaultSyscalls {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        DefaultSyscalls {
+            input: BufReader::new(input),
+            output,
+        }
+    }
+
+    fn write(&mut self, value: impl fmt::Display) -> Result<(), Trap> {
+        write!(self.output, "{value}").map_err(Trap::Io)?;
+        self.output.flush().map_err(Trap::Io)
+    }
+}
+
+impl Default for DefaultSyscalls {
+    fn default() -> Self {
+        DefaultSyscalls::new(Box::new(io::stdin()), Box::new(io::stdout()))
+    }
+}
+
+impl fmt::Debug for DefaultSyscalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DefaultSyscalls").finish_non_exhaustive()
+    }
+}
+
+impl SyscallHandler for DefaultSyscalls {
+    fn call(&mut self, number: u16, vm: &mut Vm) -> Result<(), Trap> {
+        let argument = vm.registers()[ARG_REGISTER as usize];
+        match number {
+            EXIT => Err(Trap::Exit(argument as i32)),
+            PRINT_INT => self.write(argument),
+            PRINT_CHAR => {
+                let char = u32::try_from(argument)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(Trap::InvalidArgument)?;
+                self.write(char)
+            }
+            READ_INT => {
+                let mut buffer = String::new();
+                self.input.read_line(&mut buffer).map_err(Trap::Io)?;
+                let value = buffer
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| Trap::InvalidArgument)?;
+                vm.set_register(ARG_REGISTER, value);
+                Ok(())
+            }
+            READ_CHAR => {
+                // Reads a single byte, `-1` signals the end of input.
+                let mut byte = [0];
+                let value = match self.input.read(&mut byte) {
+                    Ok(0) => -1,
+                    Ok(_) => byte[0] as i64,
+                    Err(err) => return Err(Trap::Io(err)),
+                };
+                vm.set_register(ARG_REGISTER, value);
+                Ok(())
+            }
+            number => Err(Trap::UnknownSyscall(number)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::vm::Error;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Doubler {
+        calls: usize,
+    }
+
+    impl SyscallHandler for Doubler {
+        fn call(&mut self, number: u16, vm: &mut Vm) -> Result<(), Trap> {
+            self.calls += 1;
+            match number {
+                7 => {
+                    vm.set_register(1, vm.registers()[1] * 2);
+                    Ok(())
+                }
+                number => Err(Trap::UnknownSyscall(number)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_handler() {
+        let mut test_vm = Vm::builder()
+            .register(1, 21)
+            .program(vec![20, 0, 7, 0, 20, 0, 8, 0])
+            .syscalls(Doubler::default())
+            .build()
+            .unwrap();
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.registers()[1], 42);
+        let result = test_vm.run_once();
+        assert!(matches!(
+            result,
+            Err(Error::Trap(Trap::UnknownSyscall(8), _))
+        ));
+    }
+
+    #[test]
+    fn test_no_handler() {
+        let program = vec![20, 0, 1, 0];
+        let mut test_vm = Vm::builder().program(program).build().unwrap();
+        let result = test_vm.run_once();
+        assert!(matches!(
+            result,
+            Err(Error::Trap(Trap::UnknownSyscall(1), _))
+        ));
+    }
+
+    #[test]
+    fn test_default_exit() {
+        let mut test_vm = Vm::builder()
+            .register(0, 3)
+            .program(vec![20, 0, 0, 0, 2, 1, 0, 1])
+            .syscalls(DefaultSyscalls::default())
+            .build()
+            .unwrap();
+        assert!(test_vm.run().is_ok());
+        assert_eq!(test_vm.exit_code(), 3);
+        assert_eq!(test_vm.registers()[1], 0);
+    }
+
+    #[test]
+    fn test_default_io() {
+        // read-int, print-int, load '!', print-char, read-char, halt
+        let program = vec![
+            20, 0, 3, 0, 20, 0, 1, 0, 2, 0, 0, 33, 20, 0, 2, 0, 20, 0, 4, 0, 1,
+            0, 0, 0,
+        ];
+        let output = SharedBuffer::default();
+        let input = Box::new(io::Cursor::new(b"-12\nx"));
+        let mut test_vm = Vm::builder()
+            .program(program)
+            .syscalls(DefaultSyscalls::new(input, Box::new(output.clone())))
+            .build()
+            .unwrap();
+        assert!(test_vm.run().is_ok());
+        assert_eq!(output.0.borrow().as_slice(), b"-12!");
+        assert_eq!(test_vm.registers()[0], b'x' as i64);
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+
+use crate::debug_info::DebugInfo;
+
+/// One executed instruction along with the state it changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub instr: String,
+    /// `(register, old, new)` for every register that changed.
+    pub registers: Vec<(u8, i64, i64)>,
+    pub equal_flag: Option<(bool, bool)>,
+    pub remainder: Option<(i64, i64)>,
+    pub exit_code: Option<(i32, i32)>,
+}
+
+/// Renders the record as a single line, e.g.
+/// `0008: dec $0 | $0: 3 -> 2, eq: false -> true`.
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}: {}", self.pc, self.instr)?;
+        let mut deltas = vec![];
+        for (register, old, new) in &self.registers {
+            deltas.push(format!("${register}: {old} -> {new}"));
+        }
+        if let Some((old, new)) = self.equal_flag {
+            deltas.push(format!("eq: {old} -> {new}"));
+        }
+        if let Some((old, new)) = self.remainder {
+            deltas.push(format!("rem: {old} -> {new}"));
+        }
+        if let Some((old, new)) = self.exit_code {
+            deltas.push(format!("exit: {old} -> {new}"));
+        }
+        if !deltas.is_empty() {
+            write!(f, " | {}", deltas.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub trait TraceSink: fmt::Debug {
+    fn record(&mut self, record: &TraceRecord);
+}
+
+impl TraceSink for Vec<TraceRecord> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.push(record.clone());
+    }
+}
+
+/// Keeps the most recent records, dropping the oldest once `capacity` is
+/// exceeded. Popped records can be undone with [`crate::vm::Vm::rewind`].
+#[derive(Debug)]
+pub struct History {
+    records: VecDeque<TraceRecord>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            records: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Removes and returns the most recent record.
+    pub fn pop(&mut self) -> Option<TraceRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl TraceSink for History {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        if self.capacity > 0 {
+            self.records.push_back(record.clone());
+        }
+    }
+}
+
+/// Line output of the text sinks, [`TextTrace`] and
+/// [`crate::watchpoints::TextHits`]. A failed write doesn't stop the program,
+/// the line is counted as dropped instead.
+pub struct LineWriter<W: Write> {
+    output: W,
+    debug_info: Option<DebugInfo>,
+    dropped: usize,
+}
+
+impl<W: Write> LineWriter<W> {
+    pub fn new(output: W, debug_info: Option<DebugInfo>) -> Self {
+        LineWriter {
+            output,
+            debug_info,
+            dropped: 0,
+        }
+    }
+
+    /// Writes `line`, followed by the source location of the instruction at
+    /// `pc` between `before` and `after` when it is known.
+    pub fn write_line(
+        &mut self,
+        line: impl fmt::Display,
+        pc: usize,
+        (before, after): (&str, &str),
+    ) {
+        let location = self
+            .debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.location(pc));
+        let result = match location {
+            Some(location) => {
+                writeln!(self.output, "{line}{before}{location}{after}")
+            }
+            None => writeln!(self.output, "{line}"),
+        };
+        self.dropped += usize::from(result.is_err());
+    }
+
+    /// Returns the number of lines that couldn't be written.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+/// Writes every record as a line of text.
+pub struct TextTrace<W: Write> {
+    lines: LineWriter<W>,
+}
+
+impl<W: Write> TextTrace<W> {
+    pub fn new(output: W) -> Self {
+        TextTrace {
+            lines: LineWriter::new(output, None),
+        }
+    }
+
+    /// Ends every line with the source location of the instruction, e.g.
+    /// `0008: dec $0 | $0: 3 -> 2  ; examples/countdown.rbvm:3`.
+    pub fn with_debug_info(output: W, debug_info: DebugInfo) -> Self {
+        TextTrace {
+            lines: LineWriter::new(output, Some(debug_info)),
+        }
+    }
+
+    /// Returns the number of records that couldn't be written.
+    pub fn dropped(&self) -> usize {
+        self.lines.dropped()
+    }
+}
+
+impl<W: Write> fmt::Debug for TextTrace<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TextTrace").finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> TraceSink for TextTrace<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.lines.write_line(record, record.pc, ("  ; ", ""));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_trace_records() {
+        // load $1 #7, load $2 #2, div $1 $2 $3, eq $3 $3, halt
+        let program =
+            vec![2, 1, 0, 7, 2, 2, 0, 2, 6, 1, 2, 3, 10, 3, 3, 0, 1, 0, 0, 0];
+        let mut test_vm = Vm::builder().program(program).build().unwrap();
+        let records = Rc::new(RefCell::new(vec![]));
+        test_vm.set_trace_sink(records.clone());
+        assert!(test_vm.run().is_ok());
+
+        let lines: Vec<_> =
+            records.borrow().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "0000: load $1 #7 | $1: 0 -> 7",
+                "0004: load $2 #2 | $2: 0 -> 2",
+                "0008: div $1 $2 $3 | $3: 0 -> 3, rem: 0 -> 1",
+                "0012: eq $3 $3 | eq: false -> true",
+                "0016: halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_trace() {
+        let record = TraceRecord {
+            pc: 4,
+            instr: "inc $0".to_string(),
+            registers: vec![(0, 1, 2)],
+            equal_flag: None,
+            remainder: None,
+            exit_code: None,
+        };
+        let mut output = vec![];
+        TextTrace::new(&mut output).record(&record);
+        assert_eq!(output, b"0004: inc $0 | $0: 1 -> 2\n");
+
+        let mut assembler = crate::assembler::Assembler::default();
+        assembler.set_file("test.rbvm");
+        assembler.assemble("halt\n\ninc $0\n").unwrap();
+        let debug_info = assembler.debug_info().clone();
+        let mut output = vec![];
+        TextTrace::with_debug_info(&mut output, debug_info).record(&record);
+        assert_eq!(output, b"0004: inc $0 | $0: 1 -> 2  ; test.rbvm:3\n");
+
+        let mut full = [0; 40];
+        let mut trace = TextTrace::new(&mut full[..]);
+        trace.record(&record);
+        assert_eq!(trace.dropped(), 0);
+        trace.record(&record);
+        assert_eq!(trace.dropped(), 1);
+    }
+
+    #[test]
+    fn test_history_rewind() {
+        // load $0 #2, inc $0, eq $0 $0, halt #3
+        let program = vec![2, 0, 0, 2, 18, 0, 0, 0, 10, 0, 0, 0, 1, 0, 3, 0];
+        let mut test_vm = Vm::builder().program(program).build().unwrap();
+        let history = Rc::new(RefCell::new(History::new(3)));
+        test_vm.set_trace_sink(history.clone());
+        assert_eq!(test_vm.run().unwrap(), 3);
+        assert_eq!(history.borrow().len(), 3);
+        let last = history.borrow().records.back().unwrap().to_string();
+        assert_eq!(last, "0012: halt #3 | exit: 0 -> 3");
+
+        for pc in [12, 8, 4] {
+            let record = history.borrow_mut().pop().unwrap();
+            test_vm.rewind(&record);
+            assert_eq!(test_vm.pc(), pc);
+        }
+        assert!(history.borrow().is_empty());
+        assert_eq!(test_vm.registers()[0], 2);
+        assert!(!test_vm.equal_flag());
+        assert_eq!(test_vm.exit_code(), 0);
+        assert_eq!(test_vm.run().unwrap(), 3);
+        assert_eq!(test_vm.registers()[0], 3);
+    }
+}
@@ -30,3 +30,59 @@ impl<const L: usize, const H: usize> std::ops::Deref
         &self.0
     }
 }
+
+/// Register word width. Registers are always stored as `i64`; in 32-bit mode
+/// every written value is wrapped to the `i32` range.
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Width {
+    #[default]
+    W32,
+    W64,
+}
+
+impl Width {
+    pub fn bits(&self) -> u8 {
+        match self {
+            Width::W32 => 32,
+            Width::W64 => 64,
+        }
+    }
+
+    pub fn wrap(&self, value: i64) -> i64 {
+        match self {
+            Width::W32 => value as i32 as i64,
+            Width::W64 => value,
+        }
+    }
+}
+
+impl TryFrom<u8> for Width {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            32 => Ok(Width::W32),
+            64 => Ok(Width::W64),
+            _ => Err("Unsupported width"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_width_wrap() {
+        let value = i32::MAX as i64 + 1;
+        assert_eq!(Width::W32.wrap(value), i32::MIN as i64);
+        assert_eq!(Width::W64.wrap(value), value);
+    }
+
+    #[test]
+    fn test_width_from_bits() {
+        assert_eq!(Width::try_from(32), Ok(Width::W32));
+        assert_eq!(Width::try_from(64), Ok(Width::W64));
+        assert!(Width::try_from(16).is_err());
+    }
+}
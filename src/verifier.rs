@@ -0,0 +1,371 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::cfg::{Cfg, EdgeKind, Target};
+use crate::header::{self, Header};
+use crate::instruction::{DecodedInstruction as I, INSTRUCTION_LEN};
+use crate::vm::Config;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidHeader(header::Error),
+    WidthMismatch,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidHeader(_) => write!(f, "invalid bytecode header"),
+            Error::WidthMismatch => {
+                write!(f, "bytecode needs wider registers than the VM has")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidHeader(err) => Some(err),
+            Error::WidthMismatch => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Problem {
+    /// The code ends in the middle of an instruction.
+    Truncated,
+    IllegalOpcode(u8),
+    /// Bytes the opcode doesn't use aren't zero.
+    NonZeroPadding,
+    InvalidRegister(u8),
+    MisalignedTarget(usize),
+    TargetOutOfBounds(usize),
+    /// Execution can run past the last instruction without a `halt`.
+    FallsOffEnd,
+    /// The register is read while no path from the start has written it,
+    /// so it still holds its initial zero.
+    UninitializedRead(u8),
+}
+
+/// A problem found by [`verify`] at the instruction starting at `pc`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Finding {
+    pub pc: usize,
+    pub problem: Problem,
+}
+
+impl Finding {
+    /// Warnings point at suspicious code that the VM still runs.
+    pub fn is_warning(&self) -> bool {
+        matches!(self.problem, Problem::UninitializedRead(_))
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = if self.is_warning() {
+            "warning"
+        } else {
+            "error"
+        };
+        write!(f, "{:04}: {severity}: ", self.pc)?;
+        match self.problem {
+            Problem::Truncated => {
+                write!(f, "instruction runs past the end of the code")
+            }
+            Problem::IllegalOpcode(opcode) => {
+                write!(f, "illegal opcode {opcode}")
+            }
+            Problem::NonZeroPadding => {
+                write!(f, "unused operand bytes aren't zero")
+            }
+            Problem::InvalidRegister(register) => {
+                write!(f, "register ${register} doesn't exist")
+            }
+            Problem::MisalignedTarget(target) => {
+                write!(f, "jump target {target:04} isn't an instruction")
+            }
+            Problem::TargetOutOfBounds(target) => {
+                write!(f, "jump target {target:04} is outside the program")
+            }
+            Problem::FallsOffEnd => {
+                write!(f, "execution runs past the end without a halt")
+            }
+            Problem::UninitializedRead(register) => {
+                write!(f, "${register} is read before it is written")
+            }
+        }
+    }
+}
+
+/// Parses the header of `bytecode` and verifies its code for a VM with the
+/// given configuration.
+pub fn verify_bytecode(
+    bytecode: &[u8],
+    config: Config,
+) -> Result<Vec<Finding>, Error> {
+    let (header, code) =
+        Header::parse(bytecode).map_err(Error::InvalidHeader)?;
+    if header.width > config.width {
+        return Err(Error::WidthMismatch);
+    }
+    Ok(verify(code, config.registers))
+}
+
+/// Checks code without a header for a VM with `registers` registers, without
+/// running it. Findings are sorted by pc.
+///
+/// Jump targets are checked where the CFG can work them out, see [`Cfg`].
+/// When every jump target is known, control flow is only checked in code
+/// reachable from the start, and reads of registers that were never written
+/// are reported.
+pub fn verify(code: &[u8], registers: usize) -> Vec<Finding> {
+    let mut findings = vec![];
+    let chunks = code.chunks_exact(INSTRUCTION_LEN);
+    if !chunks.remainder().is_empty() {
+        findings.push(Finding {
+            pc: code.len() - chunks.remainder().len(),
+            problem: Problem::Truncated,
+        });
+    }
+    let instrs: Vec<I> = chunks.map(I::decode).collect();
+    for (index, (instr, bytes)) in instrs
+        .iter()
+        .zip(code.chunks_exact(INSTRUCTION_LEN))
+        .enumerate()
+    {
+        let pc = index * INSTRUCTION_LEN;
+        let mut problems = vec![];
+        if let I::Illegal { opcode } = instr {
+            problems.push(Problem::IllegalOpcode(*opcode));
+        } else if instr.encode() != bytes {
+            problems.push(Problem::NonZeroPadding);
+        }
+        let (reads, writes) = instr.registers();
+        let invalid: BTreeSet<u8> = reads
+            .into_iter()
+            .chain(writes)
+            .filter(|register| *register as usize >= registers)
+            .collect();
+        problems.extend(invalid.into_iter().map(Problem::InvalidRegister));
+        findings.extend(
+            problems.into_iter().map(|problem| Finding { pc, problem }),
+        );
+    }
+    if instrs.is_empty() {
+        findings.push(Finding {
+            pc: 0,
+            problem: Problem::FallsOffEnd,
+        });
+        return findings;
+    }
+
+    let cfg = Cfg::from_instructions(instrs, &BTreeMap::new());
+    let unknown = jumps_unknown(&cfg);
+    for block in cfg.blocks.iter().filter(|block| unknown || block.reachable) {
+        let pc = block.end - INSTRUCTION_LEN;
+        for edge in &block.successors {
+            let problem = match edge.target {
+                Target::End if edge.kind == EdgeKind::Next => {
+                    Problem::FallsOffEnd
+                }
+                Target::Misaligned(target) => Problem::MisalignedTarget(target),
+                Target::OutOfBounds(target) => {
+                    Problem::TargetOutOfBounds(target)
+                }
+                _ => continue,
+            };
+            findings.push(Finding { pc, problem });
+        }
+    }
+    if !unknown {
+        findings.extend(uninitialized_reads(&cfg));
+    }
+    findings.sort_by_key(|finding| finding.pc);
+    findings
+}
+
+/// Checks for jumps to unknown targets in reachable code, after which any
+/// block could run.
+fn jumps_unknown(cfg: &Cfg) -> bool {
+    cfg.blocks.iter().any(|block| {
+        block.reachable
+            && block
+                .successors
+                .iter()
+                .any(|edge| edge.target == Target::Unknown)
+    })
+}
+
+/// Finds reads of registers that no path from the start writes first.
+fn uninitialized_reads(cfg: &Cfg) -> Vec<Finding> {
+    // Registers possibly written on some path to the start of each block.
+    let mut written: Vec<Option<BTreeSet<u8>>> = vec![None; cfg.blocks.len()];
+    written[0] = Some(BTreeSet::new());
+    let mut worklist = vec![0];
+    while let Some(index) = worklist.pop() {
+        let block = &cfg.blocks[index];
+        let mut registers = written[index].clone().unwrap_or_default();
+        for instr in cfg.block_instrs(block) {
+            registers.extend(instr.writes());
+        }
+        for edge in &block.successors {
+            let Target::Block(offset) = edge.target else {
+                continue;
+            };
+            let target = cfg
+                .blocks
+                .binary_search_by_key(&offset, |block| block.start)
+                .expect("successors start blocks");
+            let reached = written[target].is_none();
+            let state = written[target].get_or_insert_default();
+            let before = state.len();
+            state.extend(&registers);
+            if reached || state.len() != before {
+                worklist.push(target);
+            }
+        }
+    }
+
+    let mut findings = vec![];
+    for (block, registers) in cfg.blocks.iter().zip(written) {
+        // Unreachable code never reads anything.
+        let Some(mut registers) = registers else {
+            continue;
+        };
+        for (index, instr) in cfg.block_instrs(block).iter().enumerate() {
+            let reads = instr.registers().0;
+            let pc = block.start + index * INSTRUCTION_LEN;
+            let unwritten: BTreeSet<u8> = reads
+                .into_iter()
+                .filter(|register| !registers.contains(register))
+                .collect();
+            findings.extend(unwritten.into_iter().map(|register| Finding {
+                pc,
+                problem: Problem::UninitializedRead(register),
+            }));
+            registers.extend(instr.writes());
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn assembled(source_code: &str) -> Vec<u8> {
+        let mut assembler = Assembler::default();
+        assembler.assemble(source_code).unwrap()
+    }
+
+    fn finding(pc: usize, problem: Problem) -> Finding {
+        Finding { pc, problem }
+    }
+
+    #[test]
+    fn test_verify_clean() {
+        let bytecode = assembled(
+            "load $0 #3
+load $1 @loop
+load $2 #0
+loop: dec $0
+eq $0 $2
+jne $1
+halt
+",
+        );
+        assert_eq!(verify_bytecode(&bytecode, Config::default()), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_verify_instructions() {
+        // load $40 #1, add $0 $0 $0 with a stray byte, opcode 99, which
+        // falls through when resumed, half an instruction.
+        let code = [2, 40, 0, 1, 3, 0, 0, 0, 10, 0, 0, 7, 99, 0, 0, 0, 1, 0];
+        assert_eq!(
+            verify(&code, 32),
+            [
+                finding(0, Problem::InvalidRegister(40)),
+                finding(4, Problem::UninitializedRead(0)),
+                finding(8, Problem::NonZeroPadding),
+                finding(12, Problem::IllegalOpcode(99)),
+                finding(12, Problem::FallsOffEnd),
+                finding(16, Problem::Truncated),
+            ]
+        );
+        assert_eq!(verify(&[], 32), [finding(0, Problem::FallsOffEnd)]);
+    }
+
+    #[test]
+    fn test_verify_control_flow() {
+        let bytecode = assembled(
+            "load $0 #6
+load $1 #16
+je $0
+jump $1
+load $2 #100
+jump $2
+",
+        );
+        let code = &bytecode[header::HEADER_LEN..];
+        assert_eq!(
+            verify(code, 32),
+            [
+                finding(8, Problem::MisalignedTarget(6)),
+                finding(20, Problem::TargetOutOfBounds(100)),
+            ]
+        );
+
+        let code =
+            &assembled("load $0 #0\nje $0\nload $1 #0\n")[header::HEADER_LEN..];
+        assert_eq!(verify(code, 32), [finding(8, Problem::FallsOffEnd)]);
+
+        // Nothing runs past the `halt`.
+        let code = &assembled("halt\nload $0 #3\njump $0\nload $1 #0\n")
+            [header::HEADER_LEN..];
+        assert_eq!(verify(code, 32), []);
+        // Unless a jump could go anywhere.
+        let code = &assembled("syscall #1\njump $0\nhalt\nload $1 #0\n")
+            [header::HEADER_LEN..];
+        assert_eq!(verify(code, 32), [finding(12, Problem::FallsOffEnd)]);
+    }
+
+    #[test]
+    fn test_verify_uninitialized_reads() {
+        // `$1` is written on one path into `join` only, `$3` on none.
+        let bytecode = assembled(
+            "load $0 @join
+je $0
+load $1 #1
+join: add $1 $3 $2
+syscall #1
+inc $3
+halt
+",
+        );
+        let findings = verify_bytecode(&bytecode, Config::default()).unwrap();
+        assert_eq!(findings, [finding(12, Problem::UninitializedRead(3))]);
+        assert!(findings[0].is_warning());
+        assert_eq!(
+            findings[0].to_string(),
+            "0012: warning: $3 is read before it is written"
+        );
+
+        // The target of `jump $5` is unknown, so anything could follow it.
+        let code =
+            &assembled("jump $5\nadd $1 $1 $1\nhalt\n")[header::HEADER_LEN..];
+        assert_eq!(verify(code, 32), []);
+    }
+
+    #[test]
+    fn test_verify_bytecode_header() {
+        assert_eq!(
+            verify_bytecode(b"RB", Config::default()),
+            Err(Error::InvalidHeader(header::Error::Truncated))
+        );
+    }
+}
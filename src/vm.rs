@@ -1,17 +1,137 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::debug_info::{self, DebugInfo};
+use crate::disassembler::disassemble_instruction;
+use crate::fusion::{self, Superinstruction};
+use crate::header::{self, Header};
+use crate::instruction::DecodedInstruction::{self, self as I};
+use crate::instruction::INSTRUCTION_LEN;
 use crate::opcodes::Opcode;
-use crate::types::BoundedUsize;
+use crate::profiler::Profile;
+use crate::snapshot::Snapshot;
+use crate::syscalls::{SyscallHandler, Trap};
+use crate::trace::{TraceRecord, TraceSink};
+use crate::types::{BoundedUsize, Width};
+use crate::watchpoints::{Action, Hit, HitSink, Watchpoint};
 
 const IGNORE_HALTED: bool = false;
 const IGNORE_ILLEGAL: bool = false;
 
+pub const DEFAULT_REGISTERS: usize = 8;
+/// Register operands are encoded as a single byte.
+pub const MAX_REGISTERS: usize = 256;
+
 #[derive(Debug)]
 pub enum Error {
     HaltEncountered,
-    IllegalOpcode,
-    InstructionNotParsed,
+    IllegalOpcode(Box<Fault>),
+    DivisionByZero(Box<Fault>),
+    JumpOutOfRange(Box<Fault>),
     ReachedEof,
+    InvalidHeader(header::Error),
+    InvalidDebugInfo(debug_info::Error),
+    WidthMismatch,
+    Trap(Trap, Box<Fault>),
+    WatchpointHit,
+    InvalidState(State),
+    InvalidRegisterCount(usize),
+    RegisterOutOfRange(usize),
+    InvalidEntry(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::HaltEncountered => write!(f, "halt encountered"),
+            Error::IllegalOpcode(fault) => write!(f, "illegal opcode {fault}"),
+            Error::DivisionByZero(fault) => {
+                write!(f, "division by zero {fault}")
+            }
+            Error::JumpOutOfRange(fault) => {
+                write!(f, "relative jump out of range {fault}")
+            }
+            Error::ReachedEof => write!(f, "reached the end of the program"),
+            Error::InvalidHeader(_) => write!(f, "invalid bytecode header"),
+            Error::InvalidDebugInfo(_) => write!(f, "invalid debug info"),
+            Error::WidthMismatch => {
+                write!(f, "bytecode needs wider registers than the VM has")
+            }
+            Error::Trap(_, fault) => write!(f, "syscall failed {fault}"),
+            Error::WatchpointHit => write!(f, "watchpoint hit"),
+            Error::InvalidState(state) => {
+                write!(f, "operation is not allowed in the {state:?} state")
+            }
+            Error::InvalidRegisterCount(count) => write!(
+                f,
+                "{count} registers requested, expected 1 to {MAX_REGISTERS}"
+            ),
+            Error::RegisterOutOfRange(register) => {
+                write!(f, "register ${register} doesn't exist")
+            }
+            Error::InvalidEntry(pc) => {
+                write!(f, "entry point {pc:04} isn't an instruction")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidHeader(err) => Some(err),
+            Error::InvalidDebugInfo(err) => Some(err),
+            Error::Trap(trap, _) => Some(trap),
+            _ => None,
+        }
+    }
+}
+
+/// Machine context captured when an instruction faults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub pc: usize,
+    /// Raw bytes of the faulting instruction.
+    pub bytes: Vec<u8>,
+    pub opcode: Opcode,
+    pub registers: Vec<i64>,
 }
 
+/// Renders the fault as
+/// `at 0004 (div $0 $1 $2: 06 00 01 02), registers: [1, 0, 0]`.
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<_> = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        write!(
+            f,
+            "at {:04} ({}: {}), registers: {:?}",
+            self.pc,
+            disassemble_instruction(&self.bytes),
+            bytes.join(" "),
+            self.registers
+        )
+    }
+}
+
+/// Execution state of a [`Vm`]:
+/// ```text
+///               fault                  resume()
+///   Executing ---------> Crashed -------------------> Resumed
+///   |  ^   ^                                             |
+///   |  |   +------------- next instruction --------------+
+///   |  +----- code appended -----+
+///   |                            |
+///   +-- end of program ---> ReachedEof
+///   |
+///   +-- halt, exit syscall --> Halted
+/// ```
+/// `Halted` and `Crashed` VMs don't execute instructions. [`Vm::reset`]
+/// returns from any state to `Executing`.
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum State {
     #[default]
@@ -22,296 +142,948 @@ pub enum State {
     Crashed,
 }
 
-#[derive(Debug, Default, Clone)]
+impl TryFrom<u8> for State {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        #[rustfmt::skip]
+        let state = match value {
+            0   => State::Executing,
+            1   => State::Resumed,
+            2   => State::Halted,
+            3   => State::ReachedEof,
+            4   => State::Crashed,
+            _   => return Err("Invalid state"),
+        };
+        Ok(state)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub width: Width,
+    pub registers: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            width: Width::default(),
+            registers: DEFAULT_REGISTERS,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Vm {
-    pub registers: [i32; 8],
+    registers: Vec<i64>,
     pc: usize,
-    pub program: Vec<u8>,
-    remainder: u32,
+    program: Vec<u8>,
+    decoded: DecodeCache,
+    remainder: i64,
     equal_flag: bool,
     state: State,
+    config: Config,
+    exit_code: i32,
+    syscalls: Option<Rc<RefCell<dyn SyscallHandler>>>,
+    instr_pc: usize,
+    watchpoints: Vec<Watchpoint>,
+    watch_hits: Vec<Hit>,
+    watch_sink: Option<Rc<RefCell<dyn HitSink>>>,
+    watch_break: bool,
+    trace: Option<Rc<RefCell<dyn TraceSink>>>,
+    profile: Option<Profile>,
+    debug_info: Option<DebugInfo>,
+}
+
+/// Instructions decoded ahead of time by [`Vm::enable_predecoding`]. The
+/// cache is built on first use and dropped whenever the program changes.
+#[derive(Clone, Default)]
+struct DecodeCache {
+    enabled: bool,
+    fusion: bool,
+    instrs: Option<Vec<DecodedInstruction>>,
+    /// Superinstructions by instruction index, built along with `instrs`
+    /// when fusion is enabled.
+    fused: Vec<Option<Superinstruction>>,
+}
+
+impl DecodeCache {
+    fn invalidate(&mut self) {
+        self.instrs = None;
+    }
+
+    fn instrs(&mut self, program: &[u8]) -> &[DecodedInstruction] {
+        self.instrs.get_or_insert_with(|| {
+            let instrs: Vec<_> = program
+                .chunks(INSTRUCTION_LEN)
+                .map(DecodedInstruction::decode)
+                .collect();
+            if self.fusion {
+                self.fused = fusion::fuse(&instrs);
+            }
+            instrs
+        })
+    }
+
+    /// Returns the instruction at `pc`. Jumps can land between instruction
+    /// boundaries, those are decoded from the bytes.
+    fn fetch(&mut self, program: &[u8], pc: usize) -> DecodedInstruction {
+        if !self.enabled || !pc.is_multiple_of(INSTRUCTION_LEN) {
+            return DecodedInstruction::decode(&program[pc..]);
+        }
+        self.instrs(program)[pc / INSTRUCTION_LEN]
+    }
+
+    /// Returns the superinstruction starting at `pc`, if any.
+    fn fetch_fused(
+        &mut self,
+        program: &[u8],
+        pc: usize,
+    ) -> Option<Superinstruction> {
+        if !self.fusion || !pc.is_multiple_of(INSTRUCTION_LEN) {
+            return None;
+        }
+        self.instrs(program);
+        self.fused[pc / INSTRUCTION_LEN]
+    }
+}
+
+impl fmt::Debug for DecodeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecodeCache")
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::with_config(Config::default())
+    }
 }
 
 impl Vm {
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    /// Creates a VM with the default configuration and the given state.
+    ///
+    /// # Panics
+    ///
+    /// Panics where [`VmBuilder::build`] returns an error.
+    #[deprecated(note = "use `Vm::builder` instead")]
     pub fn new(
-        fill_registers: Option<Vec<(usize, i32)>>,
+        fill_registers: Option<Vec<(usize, i64)>>,
         pc: Option<usize>,
         program: Vec<u8>,
-        remainder: Option<u32>,
+        remainder: Option<i64>,
         equal_flag: Option<bool>,
     ) -> Vm {
-        let mut registers: [i32; 8] = [0; 8];
-        if let Some(fill_registers) = fill_registers {
-            for (reg_index, value) in &fill_registers {
-                let filled_register =
-                    BoundedUsize::<0, 8>::fallible_new(*reg_index).unwrap();
-                registers[*filled_register] = *value;
-            }
+        let mut builder = Vm::builder()
+            .program(program)
+            .entry(pc.unwrap_or_default())
+            .remainder(remainder.unwrap_or_default())
+            .equal_flag(equal_flag.unwrap_or_default());
+        for (register, value) in fill_registers.unwrap_or_default() {
+            builder = builder.register(register, value);
         }
-        let pc = pc.unwrap_or_default();
-        let remainder = remainder.unwrap_or_default();
-        let equal_flag = equal_flag.unwrap_or_default();
-        let state = State::default();
+        builder.build().expect("invalid VM state")
+    }
+
+    /// Creates an empty VM. The register count is clamped to
+    /// `1..=MAX_REGISTERS`.
+    pub fn with_config(mut config: Config) -> Vm {
+        config.registers =
+            *BoundedUsize::<1, MAX_REGISTERS>::new(config.registers);
         Vm {
-            registers,
-            pc,
-            program,
-            remainder,
-            equal_flag,
-            state,
+            registers: vec![0; config.registers],
+            pc: 0,
+            program: vec![],
+            decoded: DecodeCache::default(),
+            remainder: 0,
+            equal_flag: false,
+            state: State::default(),
+            config,
+            exit_code: 0,
+            syscalls: None,
+            instr_pc: 0,
+            watchpoints: vec![],
+            watch_hits: vec![],
+            watch_sink: None,
+            watch_break: false,
+            trace: None,
+            profile: None,
+            debug_info: None,
         }
     }
 
-    pub fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    pub fn config(&self) -> Config {
+        self.config
     }
 
-    pub fn push_byte(&mut self, value: u8) {
-        self.program.push(value);
+    pub fn program(&self) -> &[u8] {
+        &self.program
     }
 
-    pub fn push_bytes(&mut self, values: Vec<u8>) {
-        for value in values {
-            self.program.push(value);
-        }
+    pub fn registers(&self) -> &[i64] {
+        &self.registers
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        loop {
-            let result = self.run_once();
-            match result {
-                Ok(_unit) => self.state = State::Executing,
-                Err(Error::ReachedEof) => self.state = State::ReachedEof,
-                Err(Error::HaltEncountered) => self.state = State::Halted,
-                Err(Error::IllegalOpcode) => self.state = State::Resumed,
-                Err(Error::InstructionNotParsed) => self.state = State::Crashed,
+    /// Returns a register to write to directly, skipping the wrapping and
+    /// watchpoints of [`Vm::set_register`].
+    pub fn register_mut(&mut self, register: u8) -> Option<&mut i64> {
+        self.registers.get_mut(register as usize)
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> i64 {
+        self.remainder
+    }
+
+    /// Code passed to the last `halt` or exit syscall, zero if neither ran.
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    /// Offset of the most recently executed instruction.
+    pub fn instr_pc(&self) -> usize {
+        self.instr_pc
+    }
+
+    /// Debug info embedded in the loaded bytecode, if any.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Registers the handler for `syscall` instructions. Clones of the VM
+    /// share the handler.
+    pub fn set_syscall_handler(
+        &mut self,
+        handler: impl SyscallHandler + 'static,
+    ) {
+        self.syscalls = Some(Rc::new(RefCell::new(handler)));
+    }
+
+    /// Writes a register, wrapping the value to the configured width.
+    pub fn set_register(&mut self, register: u8, value: i64) {
+        let new = self.config.width.wrap(value);
+        let old =
+            std::mem::replace(&mut self.registers[register as usize], new);
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        for watchpoint in &self.watchpoints {
+            if watchpoint.register == register
+                && watchpoint.condition.matches(new)
+            {
+                let hit = Hit {
+                    pc: self.instr_pc,
+                    register,
+                    old,
+                    new,
+                    action: watchpoint.action,
+                };
+                match &self.watch_sink {
+                    Some(sink) => sink.borrow_mut().hit(&hit),
+                    None => self.watch_hits.push(hit),
+                }
+                self.watch_break |= watchpoint.action == Action::Break;
             }
-            if self.state == State::Halted || self.state == State::ReachedEof {
-                break;
-            } else if self.state == State::Crashed {
-                return Err(Error::InstructionNotParsed);
+        }
+    }
+
+    /// Watches writes to a register. A matching write with [`Action::Break`]
+    /// makes [`Vm::run_once`] return [`Error::WatchpointHit`] once the writing
+    /// instruction has completed.
+    pub fn add_watchpoint(
+        &mut self,
+        watchpoint: Watchpoint,
+    ) -> Result<(), Error> {
+        if watchpoint.register as usize >= self.registers.len() {
+            return Err(Error::RegisterOutOfRange(
+                watchpoint.register as usize,
+            ));
+        }
+        self.watchpoints.push(watchpoint);
+        Ok(())
+    }
+
+    /// Sends watchpoint hits to the sink as they happen, instead of keeping
+    /// them for [`Vm::take_watch_hits`].
+    pub fn set_watch_sink(&mut self, sink: Rc<RefCell<dyn HitSink>>) {
+        self.watch_sink = Some(sink);
+    }
+
+    pub fn remove_watchpoints(&mut self, register: u8) {
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.register != register);
+    }
+
+    /// Records every instruction executed from now on into the sink.
+    pub fn set_trace_sink(&mut self, sink: Rc<RefCell<dyn TraceSink>>) {
+        self.trace = Some(sink);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            config: self.config,
+            registers: self.registers.clone(),
+            pc: self.pc,
+            program: self.program.clone(),
+            remainder: self.remainder,
+            equal_flag: self.equal_flag,
+            state: self.state,
+            exit_code: self.exit_code,
+        }
+    }
+
+    /// Replaces the machine state with the snapshot. The syscall handler,
+    /// the trace sink and the watchpoints are kept. Snapshots whose program
+    /// names registers they don't have are rejected.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        let count = snapshot.registers.len();
+        if count != snapshot.config.registers || count > MAX_REGISTERS {
+            return Err(Error::InvalidRegisterCount(count));
+        }
+        check_registers(&snapshot.program, count)?;
+        self.config = snapshot.config;
+        self.registers = snapshot.registers;
+        self.pc = snapshot.pc;
+        self.program = snapshot.program;
+        self.decoded.invalidate();
+        self.remainder = snapshot.remainder;
+        self.equal_flag = snapshot.equal_flag;
+        self.state = snapshot.state;
+        self.exit_code = snapshot.exit_code;
+        Ok(())
+    }
+
+    /// Reverts the changes recorded for one instruction. Records have to be
+    /// rewound in the reverse order of their execution.
+    pub fn rewind(&mut self, record: &TraceRecord) {
+        self.pc = record.pc;
+        for (register, old, _) in &record.registers {
+            self.registers[*register as usize] = *old;
+        }
+        if let Some((old, _)) = record.equal_flag {
+            self.equal_flag = old;
+        }
+        if let Some((old, _)) = record.remainder {
+            self.remainder = old;
+        }
+        if let Some((old, _)) = record.exit_code {
+            self.exit_code = old;
+        }
+        self.state = State::Executing;
+    }
+
+    /// Starts counting executed instructions and taken jumps.
+    pub fn enable_profiling(&mut self) {
+        self.profile.get_or_insert_default();
+    }
+
+    /// Decodes the program once and dispatches from the decoded
+    /// instructions from now on. The cache is rebuilt after the program
+    /// changes.
+    pub fn enable_predecoding(&mut self) {
+        self.decoded.enabled = true;
+    }
+
+    /// Enables predecoding and lets [`Vm::run`] execute common instruction
+    /// pairs, such as `eq` followed by `jne`, as a single superinstruction.
+    /// Tracing and profiling still see every instruction, and
+    /// [`Vm::run_once`] never executes more than one.
+    pub fn enable_fusion(&mut self) {
+        self.decoded.enabled = true;
+        self.decoded.fusion = true;
+        self.decoded.invalidate();
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Drains the watchpoint hits recorded so far, unless they go to a sink.
+    /// Hits pile up until they are taken, so long runs with logging
+    /// watchpoints should use [`Vm::set_watch_sink`].
+    pub fn take_watch_hits(&mut self) -> Vec<Hit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    pub fn push_byte(&mut self, value: u8) -> Result<(), Error> {
+        self.push_bytes(vec![value])
+    }
+
+    /// Appends bytes to the program. The instructions they complete are
+    /// checked like those of [`Vm::load_bytecode`], and nothing is appended
+    /// if one of them names a register the VM doesn't have.
+    pub fn push_bytes(&mut self, values: Vec<u8>) -> Result<(), Error> {
+        let start = self.program.len() - self.program.len() % INSTRUCTION_LEN;
+        let mut pending = self.program[start..].to_vec();
+        pending.extend(&values);
+        let complete = pending.len() - pending.len() % INSTRUCTION_LEN;
+        check_registers(&pending[..complete], self.config.registers)?;
+        self.program.extend(values);
+        self.decoded.invalidate();
+        Ok(())
+    }
+
+    /// Replaces the program, leaving the registers and pc as they are. The
+    /// program is checked like the code of [`Vm::load_bytecode`].
+    pub fn set_program(&mut self, program: Vec<u8>) -> Result<(), Error> {
+        check_registers(&program, self.config.registers)?;
+        self.program = program;
+        self.decoded.invalidate();
+        Ok(())
+    }
+
+    /// Checks the header of assembled bytecode against the VM configuration
+    /// and appends the code that follows it to the program. Embedded debug
+    /// info replaces the previous one, with offsets moved past the code that
+    /// was already loaded.
+    pub fn load_bytecode(&mut self, bytecode: &[u8]) -> Result<(), Error> {
+        let (header, code, section) =
+            Header::parse_sections(bytecode).map_err(Error::InvalidHeader)?;
+        if header.width > self.config.width {
+            return Err(Error::WidthMismatch);
+        }
+        check_registers(code, self.config.registers)?;
+        if let Some(section) = section {
+            let mut debug_info = DebugInfo::from_bytes(section)
+                .map_err(Error::InvalidDebugInfo)?;
+            for entry in &mut debug_info.entries {
+                entry.offset += self.program.len();
             }
+            self.debug_info = Some(debug_info);
         }
+        self.program.extend_from_slice(code);
+        self.decoded.invalidate();
         Ok(())
     }
 
-    pub fn run_once(&mut self) -> Result<(), Error> {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Clears the machine state, keeping the program, the configuration and
+    /// host attachments such as the syscall handler, trace sink and
+    /// watchpoints.
+    pub fn reset(&mut self) {
+        self.registers = vec![0; self.config.registers];
+        self.pc = 0;
+        self.remainder = 0;
+        self.equal_flag = false;
         self.state = State::Executing;
-        self.execute_instruction()
+        self.exit_code = 0;
+        self.instr_pc = 0;
+        self.watch_hits.clear();
+        self.watch_break = false;
+        if self.profile.is_some() {
+            self.profile = Some(Profile::default());
+        }
+    }
+
+    /// Recovers from a crash. Execution continues after the faulting
+    /// instruction. Only a halted VM can't be resumed.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        match self.state {
+            State::Halted => return Err(Error::InvalidState(State::Halted)),
+            State::Crashed => self.state = State::Resumed,
+            _ => {}
+        }
+        Ok(())
     }
 
-    fn execute_instruction(&mut self) -> Result<(), Error> {
+    /// Runs the program until it halts or reaches its end and returns the
+    /// exit code. Running a halted VM returns the exit code right away.
+    pub fn run(&mut self) -> Result<i32, Error> {
+        loop {
+            match self.advance(self.decoded.fusion) {
+                Ok(()) => {}
+                Err(Error::HaltEncountered | Error::ReachedEof) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.exit_code)
+    }
+
+    /// Executes a single instruction and moves to the next [`State`].
+    pub fn run_once(&mut self) -> Result<(), Error> {
+        self.advance(false)
+    }
+
+    /// Executes an instruction, or a pair of them if `fuse` is set and they
+    /// form a superinstruction, and moves to the next [`State`].
+    fn advance(&mut self, fuse: bool) -> Result<(), Error> {
+        match self.state {
+            State::Halted => return Err(Error::HaltEncountered),
+            State::Crashed => return Err(Error::InvalidState(State::Crashed)),
+            _ => {}
+        }
+        let result = self.step(fuse);
+        self.state = match &result {
+            Ok(()) | Err(Error::WatchpointHit) => State::Executing,
+            Err(Error::HaltEncountered) => State::Halted,
+            Err(Error::ReachedEof) => State::ReachedEof,
+            Err(_) => State::Crashed,
+        };
+        result
+    }
+
+    fn step(&mut self, fuse: bool) -> Result<(), Error> {
+        if self.trace.is_some() && self.pc < self.program.len() {
+            self.step_traced()
+        } else {
+            self.execute_checked(fuse)
+        }
+    }
+
+    /// Executes one instruction and records the state it changed.
+    #[cold]
+    #[inline(never)]
+    fn step_traced(&mut self) -> Result<(), Error> {
+        let pc = self.pc;
+        let registers = self.registers.clone();
+        let (equal_flag, remainder) = (self.equal_flag, self.remainder);
+        let exit_code = self.exit_code;
+        let result = self.execute_checked(false);
+        let record = TraceRecord {
+            pc,
+            instr: disassemble_instruction(&self.program[pc..]),
+            registers: registers
+                .iter()
+                .zip(&self.registers)
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(index, (old, new))| (index as u8, *old, *new))
+                .collect(),
+            equal_flag: (equal_flag != self.equal_flag)
+                .then_some((equal_flag, self.equal_flag)),
+            remainder: (remainder != self.remainder)
+                .then_some((remainder, self.remainder)),
+            exit_code: (exit_code != self.exit_code)
+                .then_some((exit_code, self.exit_code)),
+        };
+        if let Some(trace) = &self.trace {
+            trace.borrow_mut().record(&record);
+        }
+        result
+    }
+
+    /// Executes one instruction and reports breaking watchpoints.
+    fn execute_checked(&mut self, fuse: bool) -> Result<(), Error> {
+        let result = self.execute_instruction(fuse && self.profile.is_none());
+        if let Some(profile) = &mut self.profile
+            && !matches!(result, Err(Error::ReachedEof))
+        {
+            profile.record(self.instr_pc, self.pc);
+        }
+        if std::mem::take(&mut self.watch_break) && result.is_ok() {
+            return Err(Error::WatchpointHit);
+        }
+        result
+    }
+
+    #[rustfmt::skip]
+    fn execute_instruction(&mut self, fuse: bool) -> Result<(), Error> {
         if self.pc >= self.program.len() {
-            self.state = State::ReachedEof;
             return Err(Error::ReachedEof);
         }
-        let decoded = self.decode_opcode();
-        #[rustfmt::skip]
+        self.instr_pc = self.pc;
+        if fuse {
+            let fused = self.decoded.fetch_fused(&self.program, self.pc);
+            if let Some(fused) = fused {
+                self.execute_fused(fused);
+                return Ok(());
+            }
+        }
+        let decoded = self.decoded.fetch(&self.program, self.pc);
+        self.pc += INSTRUCTION_LEN;
         match decoded {
-            Opcode::PAD     => self.pad(),
-            Opcode::HALT    => return self.halt(),
-            Opcode::LOAD    => self.load(),
-            Opcode::ADD     => self.add(),
-            Opcode::SUB     => self.sub(),
-            Opcode::MUL     => self.mul(),
-            Opcode::DIV     => self.div(),
-            Opcode::JUMP    => self.jump(),
-            Opcode::JF      => self.jf(),
-            Opcode::JB      => self.jb(),
-            Opcode::EQ      => self.eq(),
-            Opcode::NE      => self.ne(),
-            Opcode::GT      => self.gt(),
-            Opcode::LT      => self.lt(),
-            Opcode::GTE     => self.gte(),
-            Opcode::LTE     => self.lte(),
-            Opcode::JE      => self.je(),
-            Opcode::JNE     => self.jne(),
-            Opcode::INC     => self.inc(),
-            Opcode::DEC     => self.dec(),
-            Opcode::ILL     => return self.ill(),
+            I::Pad                  => {}
+            I::Halt { code }        => return self.halt(code),
+            I::Load { reg, imm }    => self.load(reg, imm),
+            I::Add { a, b, dst }    => self.add(a, b, dst),
+            I::Sub { a, b, dst }    => self.sub(a, b, dst),
+            I::Mul { a, b, dst }    => self.mul(a, b, dst),
+            I::Div { a, b, dst }    => return self.div(a, b, dst),
+            I::Jump { target }      => self.jump(target),
+            I::Jf { offset }        => return self.jf(offset),
+            I::Jb { offset }        => return self.jb(offset),
+            I::Eq { a, b }          => self.eq(a, b),
+            I::Ne { a, b }          => self.ne(a, b),
+            I::Gt { a, b }          => self.gt(a, b),
+            I::Lt { a, b }          => self.lt(a, b),
+            I::Gte { a, b }         => self.gte(a, b),
+            I::Lte { a, b }         => self.lte(a, b),
+            I::Je { target }        => self.je(target),
+            I::Jne { target }       => self.jne(target),
+            I::Inc { reg }          => self.inc(reg),
+            I::Dec { reg }          => self.dec(reg),
+            I::Syscall { number }   => return self.syscall(number),
+            I::Illegal { .. }       => return self.ill(),
         }
         Ok(())
     }
 
-    fn pad(&mut self) {}
+    /// Executes both halves of a superinstruction. Like separate
+    /// instructions, it stops in between when a watchpoint breaks.
+    fn execute_fused(&mut self, fused: Superinstruction) {
+        match fused {
+            Superinstruction::CompareJump {
+                comparison,
+                a,
+                b,
+                jump_if,
+                target,
+            } => {
+                let (lhs, rhs) = (self.register(a), self.register(b));
+                self.equal_flag = comparison.test(lhs, rhs);
+                self.instr_pc = self.pc + INSTRUCTION_LEN;
+                self.pc += 2 * INSTRUCTION_LEN;
+                if self.equal_flag == jump_if {
+                    self.jump(target);
+                }
+            }
+            Superinstruction::StepCompare {
+                reg,
+                step,
+                comparison,
+                a,
+                b,
+            } => {
+                let value = self.register(reg).wrapping_add(step);
+                self.set_register(reg, value);
+                self.pc += INSTRUCTION_LEN;
+                if self.watch_break {
+                    return;
+                }
+                self.instr_pc = self.pc;
+                self.pc += INSTRUCTION_LEN;
+                let (lhs, rhs) = (self.register(a), self.register(b));
+                self.equal_flag = comparison.test(lhs, rhs);
+            }
+        }
+    }
 
-    fn halt(&mut self) -> Result<(), Error> {
-        self.pc += 3;
+    fn halt(&mut self, code: u16) -> Result<(), Error> {
+        self.exit_code = code as i32;
         if IGNORE_HALTED {
             return Ok(());
         }
         Err(Error::HaltEncountered)
     }
 
-    fn load(&mut self) {
-        let register = self.next_8bits();
-        let number = self.next_16bits();
-        self.registers[register as usize] = number as i32;
+    fn load(&mut self, register: u8, number: u16) {
+        self.set_register(register, number as i64);
     }
 
-    fn add(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.registers[self.next_8bits() as usize] = register0 + register1;
+    fn add(&mut self, a: u8, b: u8, dst: u8) {
+        let (register0, register1) = (self.register(a), self.register(b));
+        self.set_register(dst, register0.wrapping_add(register1));
     }
 
-    fn sub(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.registers[self.next_8bits() as usize] = register0 - register1;
+    fn sub(&mut self, a: u8, b: u8, dst: u8) {
+        let (register0, register1) = (self.register(a), self.register(b));
+        self.set_register(dst, register0.wrapping_sub(register1));
     }
 
-    fn mul(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.registers[self.next_8bits() as usize] = register0 * register1;
+    fn mul(&mut self, a: u8, b: u8, dst: u8) {
+        let (register0, register1) = (self.register(a), self.register(b));
+        self.set_register(dst, register0.wrapping_mul(register1));
     }
 
-    fn div(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.registers[self.next_8bits() as usize] = register0 / register1;
-        self.remainder = (register0 % register1) as u32;
+    fn div(&mut self, a: u8, b: u8, dst: u8) -> Result<(), Error> {
+        let (register0, register1) = (self.register(a), self.register(b));
+        if register1 == 0 {
+            return Err(Error::DivisionByZero(self.fault()));
+        }
+        self.set_register(dst, register0.wrapping_div(register1));
+        self.remainder =
+            self.config.width.wrap(register0.wrapping_rem(register1));
+        Ok(())
     }
 
-    fn jump(&mut self) {
-        let target = self.next_register();
-        self.next_16bits();
-        self.pc = target as usize;
+    fn jump(&mut self, target: u8) {
+        self.pc = self.register(target) as usize;
     }
 
-    fn jf(&mut self) {
-        let value = self.next_register();
-        self.next_16bits();
-        self.pc += value as usize;
+    /// Offsets are distances, a negative one is as invalid as a jump before
+    /// the start of the program.
+    fn jf(&mut self, offset: u8) -> Result<(), Error> {
+        let offset = usize::try_from(self.register(offset)).ok();
+        match offset.and_then(|offset| self.pc.checked_add(offset)) {
+            Some(pc) => self.pc = pc,
+            None => return Err(Error::JumpOutOfRange(self.fault())),
+        }
+        Ok(())
     }
 
-    fn jb(&mut self) {
-        let value = self.next_register();
-        self.next_16bits();
-        self.pc -= value as usize;
+    fn jb(&mut self, offset: u8) -> Result<(), Error> {
+        let offset = usize::try_from(self.register(offset)).ok();
+        match offset.and_then(|offset| self.pc.checked_sub(offset)) {
+            Some(pc) => self.pc = pc,
+            None => return Err(Error::JumpOutOfRange(self.fault())),
+        }
+        Ok(())
     }
 
-    fn eq(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 == register1;
-        self.next_8bits();
+    fn eq(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) == self.register(b);
     }
 
-    fn ne(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 != register1;
-        self.next_8bits();
+    fn ne(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) != self.register(b);
     }
 
-    fn gt(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 > register1;
-        self.next_8bits();
+    fn gt(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) > self.register(b);
     }
 
-    fn lt(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 < register1;
-        self.next_8bits();
+    fn lt(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) < self.register(b);
     }
 
-    fn gte(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 >= register1;
-        self.next_8bits();
+    fn gte(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) >= self.register(b);
     }
 
-    fn lte(&mut self) {
-        let (register0, register1) =
-            (self.next_register(), self.next_register());
-        self.equal_flag = register0 <= register1;
-        self.next_8bits();
+    fn lte(&mut self, a: u8, b: u8) {
+        self.equal_flag = self.register(a) <= self.register(b);
     }
 
-    fn je(&mut self) {
-        let target = self.next_register();
-        self.next_16bits();
+    fn je(&mut self, target: u8) {
         if self.equal_flag {
-            self.pc = target as usize;
+            self.jump(target);
         }
     }
 
-    fn jne(&mut self) {
-        let target = self.next_register();
-        self.next_16bits();
+    fn jne(&mut self, target: u8) {
         if !self.equal_flag {
-            self.pc = target as usize;
+            self.jump(target);
         }
     }
 
-    fn inc(&mut self) {
-        let register = self.next_8bits();
-        self.next_16bits();
-        self.registers[register as usize] += 1;
+    fn inc(&mut self, register: u8) {
+        let value = self.register(register).wrapping_add(1);
+        self.set_register(register, value);
     }
 
-    fn dec(&mut self) {
-        let register = self.next_8bits();
-        self.next_16bits();
-        self.registers[register as usize] -= 1;
+    fn dec(&mut self, register: u8) {
+        let value = self.register(register).wrapping_sub(1);
+        self.set_register(register, value);
+    }
+
+    fn syscall(&mut self, number: u16) -> Result<(), Error> {
+        let Some(handler) = self.syscalls.clone() else {
+            let trap = Trap::UnknownSyscall(number);
+            return Err(Error::Trap(trap, self.fault()));
+        };
+        let result = handler.borrow_mut().call(number, self);
+        match result {
+            Ok(()) => Ok(()),
+            Err(Trap::Exit(code)) => {
+                self.exit_code = code;
+                Err(Error::HaltEncountered)
+            }
+            Err(trap) => Err(Error::Trap(trap, self.fault())),
+        }
     }
 
     fn ill(&mut self) -> Result<(), Error> {
-        self.pc += 3;
         if IGNORE_ILLEGAL {
             return Ok(());
         }
-        Err(Error::IllegalOpcode)
+        Err(Error::IllegalOpcode(self.fault()))
     }
 
-    fn next_register(&mut self) -> i32 {
-        self.registers[self.next_8bits() as usize]
+    /// Captures the context of the instruction being executed.
+    fn fault(&self) -> Box<Fault> {
+        let end = (self.instr_pc + INSTRUCTION_LEN).min(self.program.len());
+        let bytes = self.program[self.instr_pc..end].to_vec();
+        Box::new(Fault {
+            pc: self.instr_pc,
+            opcode: Opcode::from(bytes[0]),
+            bytes,
+            registers: self.registers.clone(),
+        })
     }
 
-    fn next_8bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    fn register(&self, register: u8) -> i64 {
+        self.registers[register as usize]
     }
+}
 
-    fn next_16bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8)
-            | self.program[self.pc + 1] as u16;
-        self.pc += 2;
-        result
+/// Rejects code naming registers past `registers`, which would otherwise
+/// fail once the instruction runs.
+fn check_registers(code: &[u8], registers: usize) -> Result<(), Error> {
+    for bytes in code.chunks(INSTRUCTION_LEN) {
+        let (reads, writes) = DecodedInstruction::decode(bytes).registers();
+        if let Some(register) = reads
+            .into_iter()
+            .chain(writes)
+            .find(|register| *register as usize >= registers)
+        {
+            return Err(Error::RegisterOutOfRange(register as usize));
+        }
+    }
+    Ok(())
+}
+
+/// Builds a [`Vm`] with the given state, checking it before construction.
+///
+/// ```
+/// use rbvm::vm::Vm;
+///
+/// // load $0 #10, add $0 $1 $0
+/// let program = vec![2, 0, 0, 10, 3, 0, 1, 0];
+/// let mut vm = Vm::builder()
+///     .register(1, 32)
+///     .program(program)
+///     .entry(4)
+///     .build()?;
+/// vm.run()?;
+/// assert_eq!(vm.registers()[0], 32);
+/// # Ok::<(), rbvm::vm::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    config: Config,
+    registers: Vec<(usize, i64)>,
+    program: Vec<u8>,
+    entry: usize,
+    remainder: i64,
+    equal_flag: bool,
+    syscalls: Option<Rc<RefCell<dyn SyscallHandler>>>,
+}
+
+impl VmBuilder {
+    pub fn new() -> Self {
+        VmBuilder::default()
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the initial value of a register.
+    pub fn register(mut self, register: usize, value: i64) -> Self {
+        self.registers.push((register, value));
+        self
+    }
+
+    /// Sets the code to run, without a bytecode header.
+    pub fn program(mut self, program: Vec<u8>) -> Self {
+        self.program = program;
+        self
+    }
+
+    /// Sets the code offset execution starts at.
+    pub fn entry(mut self, pc: usize) -> Self {
+        self.entry = pc;
+        self
+    }
+
+    pub fn remainder(mut self, remainder: i64) -> Self {
+        self.remainder = remainder;
+        self
+    }
+
+    pub fn equal_flag(mut self, equal_flag: bool) -> Self {
+        self.equal_flag = equal_flag;
+        self
+    }
+
+    pub fn syscalls(mut self, handler: impl SyscallHandler + 'static) -> Self {
+        self.syscalls = Some(Rc::new(RefCell::new(handler)));
+        self
+    }
+
+    pub fn build(self) -> Result<Vm, Error> {
+        let config = self.config;
+        if !(1..=MAX_REGISTERS).contains(&config.registers) {
+            return Err(Error::InvalidRegisterCount(config.registers));
+        }
+        if self.entry > self.program.len()
+            || !self.entry.is_multiple_of(INSTRUCTION_LEN)
+        {
+            return Err(Error::InvalidEntry(self.entry));
+        }
+        check_registers(&self.program, config.registers)?;
+        let mut vm = Vm::with_config(config);
+        for (register, value) in self.registers {
+            if register >= config.registers {
+                return Err(Error::RegisterOutOfRange(register));
+            }
+            vm.registers[register] = config.width.wrap(value);
+        }
+        vm.program = self.program;
+        vm.pc = self.entry;
+        vm.remainder = config.width.wrap(self.remainder);
+        vm.equal_flag = self.equal_flag;
+        vm.syscalls = self.syscalls;
+        Ok(vm)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::watchpoints::Condition;
 
-    fn get_test_vm(
-        fill_registers: Option<Vec<(usize, i32)>>,
-        pc: Option<usize>,
-        program: Vec<u8>,
-    ) -> Vm {
-        Vm::new(fill_registers, pc, program, None, None)
+    fn build(registers: &[(usize, i64)], program: Vec<u8>) -> Vm {
+        let mut builder = Vm::builder().program(program);
+        for &(register, value) in registers {
+            builder = builder.register(register, value);
+        }
+        builder.build().unwrap()
     }
 
     #[test]
     fn test_create_vm() {
         let test_vm = Vm::default();
-        assert_eq!(test_vm.registers[0], 0)
+        assert_eq!(test_vm.registers()[0], 0)
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_new() {
+        let test_vm =
+            Vm::new(Some(vec![(1, 7)]), Some(4), vec![0; 8], None, None);
+        assert_eq!((test_vm.registers()[1], test_vm.pc), (7, 4));
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let result = Vm::builder().register(8, 1).build();
+        assert!(matches!(result, Err(Error::RegisterOutOfRange(8))));
+        let result = Vm::builder().program(vec![0; 8]).entry(6).build();
+        assert!(matches!(result, Err(Error::InvalidEntry(6))));
+        let result = Vm::builder().entry(4).build();
+        assert!(matches!(result, Err(Error::InvalidEntry(4))));
+        let config = Config {
+            registers: 0,
+            ..Config::default()
+        };
+        let result = Vm::builder().config(config).build();
+        assert!(matches!(result, Err(Error::InvalidRegisterCount(0))));
+        let test_vm = Vm::builder().register(1, 1 << 32).build().unwrap();
+        assert_eq!(test_vm.registers()[1], 0);
     }
 
     #[test]
     fn test_opcode_halt() {
-        let program = vec![1, 0, 0, 0];
-        let mut test_vm = get_test_vm(None, None, program);
+        let mut test_vm = build(&[], vec![1, 0, 0, 0]);
         let result = test_vm.run_once();
         assert!(result.is_err());
         assert_eq!(test_vm.pc, 4);
@@ -319,70 +1091,144 @@ mod tests {
 
     #[test]
     fn test_opcode_ill() {
-        let program = vec![200, 0, 0, 0];
-        let mut test_vm = get_test_vm(None, None, program);
+        let mut test_vm = build(&[], vec![200, 0, 0, 0]);
         let result = test_vm.run_once();
         assert!(result.is_err());
         assert_eq!(test_vm.pc, 4);
     }
 
+    #[test]
+    fn test_state_crash_and_resume() {
+        // ill, inc $0, halt #2
+        let mut test_vm =
+            build(&[], vec![200, 0, 0, 0, 18, 0, 0, 0, 1, 0, 2, 0]);
+        assert!(matches!(test_vm.run(), Err(Error::IllegalOpcode(_))));
+        assert_eq!(test_vm.state(), State::Crashed);
+        let result = test_vm.run_once();
+        assert!(matches!(result, Err(Error::InvalidState(State::Crashed))));
+        assert_eq!(test_vm.pc, 4);
+
+        assert!(test_vm.resume().is_ok());
+        assert_eq!(test_vm.state(), State::Resumed);
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.state(), State::Executing);
+        assert!(matches!(test_vm.run(), Ok(2)));
+        assert_eq!(test_vm.state(), State::Halted);
+        assert!(matches!(test_vm.resume(), Err(Error::InvalidState(_))));
+    }
+
+    #[test]
+    fn test_state_halt_and_reset() {
+        // inc $0, halt #1, inc $0
+        let mut test_vm =
+            build(&[], vec![18, 0, 0, 0, 1, 0, 1, 0, 18, 0, 0, 0]);
+        test_vm.enable_profiling();
+        assert!(matches!(test_vm.run(), Ok(1)));
+        assert!(matches!(test_vm.run(), Ok(1)));
+        assert_eq!((test_vm.pc, test_vm.registers()[0]), (8, 1));
+
+        test_vm.reset();
+        assert_eq!(test_vm.state(), State::Executing);
+        assert_eq!((test_vm.pc, test_vm.registers()[0]), (0, 0));
+        assert_eq!(
+            (test_vm.exit_code(), test_vm.profile().unwrap().total()),
+            (0, 0)
+        );
+        assert!(matches!(test_vm.run(), Ok(1)));
+        assert_eq!(test_vm.registers()[0], 1);
+    }
+
+    #[test]
+    fn test_state_reached_eof() {
+        let mut test_vm = build(&[], vec![18, 0, 0, 0]);
+        assert!(test_vm.run().is_ok());
+        assert_eq!(test_vm.state(), State::ReachedEof);
+        test_vm.push_bytes(vec![18, 0, 0, 0]).unwrap();
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.state(), State::Executing);
+        assert_eq!(test_vm.registers()[0], 2);
+    }
+
     #[test]
     fn test_opcode_load() {
         // Remember, this is how we represent `500` using two `u8`s in little
         // endian format: `1, 244`
-        let program = vec![2, 0, 1, 244];
-        let mut test_vm = get_test_vm(None, None, program);
+        let mut test_vm = build(&[], vec![2, 0, 1, 244]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
-        assert_eq!(test_vm.registers[0], 500);
+        assert_eq!(test_vm.registers()[0], 500);
     }
 
     #[test]
     fn test_opcode_add() {
-        let fill_registers = Some(vec![(1, 15), (2, 10)]);
-        let program = vec![3, 1, 2, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm = build(&[(1, 15), (2, 10)], vec![3, 1, 2, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
-        assert_eq!(test_vm.registers[0], 25);
+        assert_eq!(test_vm.registers()[0], 25);
     }
 
     #[test]
     fn test_opcode_sub() {
-        let fill_registers = Some(vec![(1, 15), (2, 10)]);
-        let program = vec![4, 1, 2, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm = build(&[(1, 15), (2, 10)], vec![4, 1, 2, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
-        assert_eq!(test_vm.registers[0], 5);
+        assert_eq!(test_vm.registers()[0], 5);
     }
 
     #[test]
     fn test_opcode_mul() {
-        let fill_registers = Some(vec![(1, 4), (2, 6)]);
-        let program = vec![5, 1, 2, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm = build(&[(1, 4), (2, 6)], vec![5, 1, 2, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
-        assert_eq!(test_vm.registers[0], 24);
+        assert_eq!(test_vm.registers()[0], 24);
     }
 
     #[test]
     fn test_opcode_div() {
-        let fill_registers = Some(vec![(1, 8), (2, 5)]);
-        let program = vec![6, 1, 2, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm = build(&[(1, 8), (2, 5)], vec![6, 1, 2, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
-        assert_eq!(test_vm.registers[0], 1);
+        assert_eq!(test_vm.registers()[0], 1);
         assert_eq!(test_vm.remainder, 3)
     }
 
+    #[test]
+    fn test_division_by_zero() {
+        let mut test_vm = build(&[(1, 8)], vec![2, 0, 0, 1, 6, 1, 2, 0]);
+        let Err(Error::DivisionByZero(fault)) = test_vm.run() else {
+            panic!("expected a division by zero");
+        };
+        assert_eq!(fault.pc, 4);
+        assert_eq!(fault.bytes, [6, 1, 2, 0]);
+        assert_eq!(fault.opcode, Opcode::DIV);
+        assert_eq!(fault.registers, [1, 8, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(test_vm.registers()[0], 1);
+        assert_eq!(
+            fault.to_string(),
+            "at 0004 (div $1 $2 $0: 06 01 02 00), registers: \
+             [1, 8, 0, 0, 0, 0, 0, 0]"
+        );
+    }
+
+    #[test]
+    fn test_error_display_and_source() {
+        use std::error::Error as _;
+
+        let mut test_vm = build(&[], vec![20, 0, 9, 0]);
+        let err = test_vm.run().unwrap_err();
+        assert!(err.to_string().starts_with("syscall failed at 0000"));
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "unknown syscall 9");
+
+        let err = test_vm.load_bytecode(&[0; 8]).unwrap_err();
+        assert_eq!(err.to_string(), "invalid bytecode header");
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "bytecode doesn't start with RBVM");
+    }
+
     #[test]
     fn test_opcode_jump() {
-        let fill_registers = Some(vec![(0, 4)]);
-        let program = vec![7, 0, 0, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm = build(&[(0, 4)], vec![7, 0, 0, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
         assert_eq!(test_vm.pc, 4);
@@ -390,9 +1236,8 @@ mod tests {
 
     #[test]
     fn test_opcode_jf() {
-        let fill_registers = Some(vec![(0, 4)]);
-        let program = vec![8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        let mut test_vm = get_test_vm(fill_registers, None, program);
+        let mut test_vm =
+            build(&[(0, 4)], vec![8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
         let result = test_vm.run_once();
         assert!(result.is_ok());
         assert_eq!(test_vm.pc, 8);
@@ -400,27 +1245,45 @@ mod tests {
 
     #[test]
     fn test_opcode_jb() {
-        let fill_registers = Some(vec![(0, 8)]);
-        let pc = Some(4);
-        let program = vec![0, 0, 0, 0, 9, 0, 0, 0];
-        let mut test_vm = get_test_vm(fill_registers, pc, program);
+        let mut test_vm = Vm::builder()
+            .register(0, 8)
+            .program(vec![0, 0, 0, 0, 9, 0, 0, 0])
+            .entry(4)
+            .build()
+            .unwrap();
         let result = test_vm.run_once();
         assert!(result.is_ok());
         assert_eq!(test_vm.pc, 0);
     }
 
+    #[test]
+    fn test_relative_jump_out_of_range() {
+        // load $0 #0, dec $0, jf $0, halt
+        let program = vec![2, 0, 0, 0, 19, 0, 0, 0, 8, 0, 0, 0, 1, 0, 0, 0];
+        let mut test_vm = build(&[], program);
+        let Err(Error::JumpOutOfRange(fault)) = test_vm.run() else {
+            panic!("expected a jump out of range");
+        };
+        assert_eq!(fault.pc, 8);
+        assert_eq!(fault.opcode, Opcode::JF);
+        assert_eq!(test_vm.state(), State::Crashed);
+
+        // jb $0 with $0 = 12 from pc 4
+        let mut test_vm = build(&[(0, 12)], vec![9, 0, 0, 0]);
+        let err = test_vm.run().unwrap_err();
+        assert!(matches!(err, Error::JumpOutOfRange(_)));
+        assert!(err.to_string().starts_with("relative jump out of range at"));
+        assert_eq!(test_vm.state(), State::Crashed);
+    }
+
     #[test]
     fn test_opcode_eq() {
-        let fill_registers0 = Some(vec![(1, 32), (2, 32)]);
-        let program0 = vec![10, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 32), (2, 32)], vec![10, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 52), (2, 38)]);
-        let program1 = vec![10, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 52), (2, 38)], vec![10, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(!test_vm1.equal_flag);
@@ -428,16 +1291,12 @@ mod tests {
 
     #[test]
     fn test_opcode_ne() {
-        let fill_registers0 = Some(vec![(1, 32), (2, 32)]);
-        let program0 = vec![11, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 32), (2, 32)], vec![11, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(!test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 52), (2, 38)]);
-        let program1 = vec![11, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 52), (2, 38)], vec![11, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(test_vm1.equal_flag);
@@ -445,16 +1304,12 @@ mod tests {
 
     #[test]
     fn test_opcode_gt() {
-        let fill_registers0 = Some(vec![(1, 8), (2, 5)]);
-        let program0 = vec![12, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 8), (2, 5)], vec![12, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 5), (2, 8)]);
-        let program1 = vec![12, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 5), (2, 8)], vec![12, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(!test_vm1.equal_flag);
@@ -462,16 +1317,12 @@ mod tests {
 
     #[test]
     fn test_opcode_lt() {
-        let fill_registers0 = Some(vec![(1, 8), (2, 5)]);
-        let program0 = vec![13, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 8), (2, 5)], vec![13, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(!test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 5), (2, 8)]);
-        let program1 = vec![13, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 5), (2, 8)], vec![13, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(test_vm1.equal_flag);
@@ -479,23 +1330,17 @@ mod tests {
 
     #[test]
     fn test_opcode_gte() {
-        let fill_registers0 = Some(vec![(1, 77), (2, 64)]);
-        let program0 = vec![14, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 77), (2, 64)], vec![14, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 21), (2, 21)]);
-        let program1 = vec![14, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 21), (2, 21)], vec![14, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(test_vm1.equal_flag);
 
-        let fill_registers2 = Some(vec![(1, 8), (2, 64)]);
-        let program2 = vec![14, 1, 2, 0];
-        let mut test_vm2 = get_test_vm(fill_registers2, None, program2);
+        let mut test_vm2 = build(&[(1, 8), (2, 64)], vec![14, 1, 2, 0]);
         let result2 = test_vm2.run_once();
         assert!(result2.is_ok());
         assert!(!test_vm2.equal_flag);
@@ -503,23 +1348,17 @@ mod tests {
 
     #[test]
     fn test_opcode_lte() {
-        let fill_registers0 = Some(vec![(1, 77), (2, 64)]);
-        let program0 = vec![15, 1, 2, 0];
-        let mut test_vm0 = get_test_vm(fill_registers0, None, program0);
+        let mut test_vm0 = build(&[(1, 77), (2, 64)], vec![15, 1, 2, 0]);
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert!(!test_vm0.equal_flag);
 
-        let fill_registers1 = Some(vec![(1, 21), (2, 21)]);
-        let program1 = vec![15, 1, 2, 0];
-        let mut test_vm1 = get_test_vm(fill_registers1, None, program1);
+        let mut test_vm1 = build(&[(1, 21), (2, 21)], vec![15, 1, 2, 0]);
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert!(test_vm1.equal_flag);
 
-        let fill_registers2 = Some(vec![(1, 8), (2, 64)]);
-        let program2 = vec![15, 1, 2, 0];
-        let mut test_vm2 = get_test_vm(fill_registers2, None, program2);
+        let mut test_vm2 = build(&[(1, 8), (2, 64)], vec![15, 1, 2, 0]);
         let result2 = test_vm2.run_once();
         assert!(result2.is_ok());
         assert!(test_vm2.equal_flag);
@@ -527,17 +1366,21 @@ mod tests {
 
     #[test]
     fn test_opcode_je() {
-        let pc0 = Some(4);
-        let program0 = vec![0, 0, 0, 0, 16, 0, 0, 0];
-        let mut test_vm0 = get_test_vm(None, pc0, program0);
+        let mut test_vm0 = Vm::builder()
+            .program(vec![0, 0, 0, 0, 16, 0, 0, 0])
+            .entry(4)
+            .build()
+            .unwrap();
         test_vm0.equal_flag = true;
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert_eq!(test_vm0.pc, 0);
 
-        let pc1 = Some(4);
-        let program1 = vec![0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0];
-        let mut test_vm1 = get_test_vm(None, pc1, program1);
+        let mut test_vm1 = Vm::builder()
+            .program(vec![0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0])
+            .entry(4)
+            .build()
+            .unwrap();
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert_eq!(test_vm1.pc, 8);
@@ -545,19 +1388,215 @@ mod tests {
 
     #[test]
     fn test_opcode_jne() {
-        let pc0 = Some(4);
-        let program0 = vec![0, 0, 0, 0, 17, 0, 0, 0];
-        let mut test_vm0 = get_test_vm(None, pc0, program0);
+        let mut test_vm0 = Vm::builder()
+            .program(vec![0, 0, 0, 0, 17, 0, 0, 0])
+            .entry(4)
+            .build()
+            .unwrap();
         let result0 = test_vm0.run_once();
         assert!(result0.is_ok());
         assert_eq!(test_vm0.pc, 0);
 
-        let pc1 = Some(4);
-        let program1 = vec![0, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 0];
-        let mut test_vm1 = get_test_vm(None, pc1, program1);
+        let mut test_vm1 = Vm::builder()
+            .program(vec![0, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 0])
+            .entry(4)
+            .build()
+            .unwrap();
         test_vm1.equal_flag = true;
         let result1 = test_vm1.run_once();
         assert!(result1.is_ok());
         assert_eq!(test_vm1.pc, 8);
     }
+
+    #[test]
+    fn test_width_arithmetic() {
+        let builder = || {
+            Vm::builder()
+                .register(1, i32::MAX as i64)
+                .register(2, 1)
+                .program(vec![3, 1, 2, 0])
+        };
+        let mut test_vm32 = builder().build().unwrap();
+        assert!(test_vm32.run_once().is_ok());
+        assert_eq!(test_vm32.registers()[0], i32::MIN as i64);
+
+        let config = Config {
+            width: Width::W64,
+            ..Config::default()
+        };
+        let mut test_vm64 = builder().config(config).build().unwrap();
+        assert!(test_vm64.run_once().is_ok());
+        assert_eq!(test_vm64.registers()[0], i32::MAX as i64 + 1);
+    }
+
+    #[test]
+    fn test_load_bytecode() {
+        let mut bytecode = Header::new(Width::W64).to_bytes();
+        bytecode.extend([1, 0, 0, 0]);
+
+        let mut test_vm32 = Vm::default();
+        let result32 = test_vm32.load_bytecode(&bytecode);
+        assert!(matches!(result32, Err(Error::WidthMismatch)));
+
+        let mut test_vm64 = Vm::with_config(Config {
+            width: Width::W64,
+            ..Config::default()
+        });
+        assert!(test_vm64.load_bytecode(&bytecode).is_ok());
+        assert_eq!(test_vm64.program, vec![1, 0, 0, 0]);
+
+        let result = test_vm64.load_bytecode(&[1, 0, 0, 0]);
+        assert!(matches!(result, Err(Error::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_load_bytecode_registers() {
+        // load $12 #1, assembled for 16 registers.
+        let mut bytecode = Header::new(Width::W32).to_bytes();
+        bytecode.extend([2, 12, 0, 1]);
+        let mut test_vm = Vm::default();
+        let result = test_vm.load_bytecode(&bytecode);
+        assert!(matches!(result, Err(Error::RegisterOutOfRange(12))));
+        assert!(test_vm.program.is_empty());
+
+        let result = Vm::builder().program(vec![3, 0, 1, 9]).build();
+        assert!(matches!(result, Err(Error::RegisterOutOfRange(9))));
+    }
+
+    #[test]
+    fn test_load_bytecode_debug_info() {
+        let mut assembler = crate::assembler::Assembler::default();
+        assembler.set_file("test.rbvm");
+        assembler.set_embed_debug_info(true);
+        let bytecode = assembler.assemble("inc $0\nhalt\n").unwrap();
+        let mut test_vm = Vm::default();
+        test_vm.push_bytes(vec![18, 0, 0, 0]).unwrap();
+        assert!(test_vm.load_bytecode(&bytecode).is_ok());
+        assert_eq!(test_vm.program.len(), 12);
+        let debug_info = test_vm.debug_info().unwrap();
+        assert_eq!(debug_info.location(8).unwrap().to_string(), "test.rbvm:2");
+        assert!(test_vm.run().is_ok());
+        assert_eq!(test_vm.instr_pc(), 8);
+    }
+
+    #[test]
+    fn test_predecoding() {
+        // load $0 #3, load $1 @loop, loop: dec $0, eq $0 $2, jne $1, halt
+        let program = vec![
+            2, 0, 0, 3, 2, 1, 0, 8, 19, 0, 0, 0, 10, 0, 2, 0, 17, 1, 0, 0, 1,
+            0, 0, 0,
+        ];
+        // load $1 #6, jump $1: lands on the last two bytes of the jump
+        let unaligned = vec![2, 1, 0, 6, 7, 1, 0, 0, 18, 0, 0, 0];
+        for program in [program, unaligned] {
+            let mut expected = build(&[], program.clone());
+            let mut test_vm = build(&[], program);
+            test_vm.enable_predecoding();
+            assert_eq!(test_vm.run().is_ok(), expected.run().is_ok());
+            assert_eq!(test_vm.registers(), expected.registers());
+            assert_eq!(test_vm.pc, expected.pc);
+            assert_eq!(test_vm.state(), expected.state());
+        }
+
+        let mut test_vm = Vm::default();
+        test_vm.enable_predecoding();
+        test_vm.push_bytes(vec![2, 0, 0, 7]).unwrap();
+        assert!(test_vm.run_once().is_ok());
+        test_vm.push_bytes(vec![2, 1, 0, 9]).unwrap();
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.registers()[..2], [7, 9]);
+
+        assert!(matches!(
+            test_vm.set_program(vec![2, 12, 0, 1]),
+            Err(Error::RegisterOutOfRange(12))
+        ));
+        assert_eq!(test_vm.program().len(), 8);
+        test_vm
+            .set_program(vec![2, 0, 0, 1, 2, 1, 0, 2, 2, 2, 0, 3])
+            .unwrap();
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.program().len(), 12);
+        assert_eq!(test_vm.registers()[..3], [7, 9, 3]);
+    }
+
+    #[test]
+    fn test_register_count() {
+        let config = Config {
+            registers: 32,
+            ..Config::default()
+        };
+        let mut test_vm = Vm::with_config(config);
+        assert_eq!(test_vm.registers().len(), 32);
+        test_vm.push_bytes(vec![2, 31, 0, 7]).unwrap();
+        assert!(test_vm.run_once().is_ok());
+        assert_eq!(test_vm.registers()[31], 7);
+        *test_vm.register_mut(30).unwrap() = 5;
+        assert_eq!(test_vm.registers()[30], 5);
+        assert!(test_vm.register_mut(32).is_none());
+        // Checked once the instruction is complete.
+        test_vm.push_bytes(vec![2, 32]).unwrap();
+        assert!(matches!(
+            test_vm.push_bytes(vec![0, 7]),
+            Err(Error::RegisterOutOfRange(32))
+        ));
+        assert_eq!(test_vm.program().len(), 6);
+
+        let config = Config {
+            registers: 1000,
+            ..Config::default()
+        };
+        let test_vm = Vm::with_config(config);
+        assert_eq!(test_vm.registers().len(), MAX_REGISTERS);
+        assert_eq!(test_vm.config().registers, MAX_REGISTERS);
+    }
+
+    #[test]
+    fn test_halt_exit_code() {
+        let mut test_vm = build(&[], vec![2, 0, 0, 1, 1, 0, 42, 0, 1, 0, 0, 0]);
+        assert_eq!(test_vm.run().unwrap(), 42);
+        assert_eq!(test_vm.exit_code(), 42);
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut test_vm =
+            build(&[], vec![2, 1, 0, 5, 18, 1, 0, 0, 18, 1, 0, 0, 1, 0, 0, 0]);
+        test_vm
+            .add_watchpoint(Watchpoint {
+                register: 1,
+                condition: Condition::Any,
+                action: Action::Log,
+            })
+            .unwrap();
+        test_vm.add_watchpoint("$1 == 7".parse().unwrap()).unwrap();
+        let result = test_vm.run();
+        assert!(matches!(result, Err(Error::WatchpointHit)));
+        assert_eq!(test_vm.pc, 12);
+        let hits = test_vm.take_watch_hits();
+        assert_eq!(hits.len(), 4);
+        assert_eq!((hits[0].pc, hits[0].old, hits[0].new), (0, 0, 5));
+        assert_eq!((hits[3].pc, hits[3].action), (8, Action::Break));
+
+        test_vm.remove_watchpoints(1);
+        assert!(test_vm.run().is_ok());
+        assert!(test_vm.take_watch_hits().is_empty());
+
+        let result = test_vm.add_watchpoint("$8".parse().unwrap());
+        assert!(matches!(result, Err(Error::RegisterOutOfRange(8))));
+    }
+
+    #[test]
+    fn test_watch_sink() {
+        // load $1 #5, inc $1, halt
+        let mut test_vm = build(&[], vec![2, 1, 0, 5, 18, 1, 0, 0, 1, 0, 0, 0]);
+        let mut watchpoint: Watchpoint = "$1".parse().unwrap();
+        watchpoint.action = Action::Log;
+        test_vm.add_watchpoint(watchpoint).unwrap();
+        let hits = Rc::new(RefCell::new(vec![]));
+        test_vm.set_watch_sink(hits.clone());
+        assert!(test_vm.run().is_ok());
+        assert_eq!(hits.borrow().len(), 2);
+        assert!(test_vm.take_watch_hits().is_empty());
+    }
 }
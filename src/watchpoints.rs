@@ -0,0 +1,208 @@
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::debug_info::DebugInfo;
+use crate::trace::LineWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    Any,
+    Eq(i64),
+    Ne(i64),
+    Gt(i64),
+    Lt(i64),
+    Gte(i64),
+    Lte(i64),
+}
+
+impl Condition {
+    #[rustfmt::skip]
+    pub fn matches(&self, value: i64) -> bool {
+        match *self {
+            Condition::Any          => true,
+            Condition::Eq(other)    => value == other,
+            Condition::Ne(other)    => value != other,
+            Condition::Gt(other)    => value > other,
+            Condition::Lt(other)    => value < other,
+            Condition::Gte(other)   => value >= other,
+            Condition::Lte(other)   => value <= other,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Stop execution after the writing instruction.
+    #[default]
+    Break,
+    /// Only record the hit.
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint {
+    pub register: u8,
+    pub condition: Condition,
+    pub action: Action,
+}
+
+/// Parses watch expressions such as `$1`, `$1 == 5` or `$2<0`. The action
+/// defaults to [`Action::Break`].
+impl FromStr for Watchpoint {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let value = value.strip_prefix('$').ok_or("Expected a register")?;
+        let split = value
+            .find(|char: char| !char.is_ascii_digit())
+            .unwrap_or(value.len());
+        let (register, rest) = value.split_at(split);
+        let register = register.parse().map_err(|_| "Invalid register")?;
+        let rest = rest.trim();
+        let condition = if rest.is_empty() {
+            Condition::Any
+        } else {
+            let split = rest
+                .find(|char: char| !"=!<>".contains(char))
+                .ok_or("Expected a value")?;
+            let (operator, operand) = rest.split_at(split);
+            let operand =
+                operand.trim().parse().map_err(|_| "Invalid value")?;
+            #[rustfmt::skip]
+            let condition = match operator.trim() {
+                "=="    => Condition::Eq(operand),
+                "!="    => Condition::Ne(operand),
+                ">"     => Condition::Gt(operand),
+                "<"     => Condition::Lt(operand),
+                ">="    => Condition::Gte(operand),
+                "<="    => Condition::Lte(operand),
+                _       => return Err("Invalid operator"),
+            };
+            condition
+        };
+        Ok(Watchpoint {
+            register,
+            condition,
+            action: Action::default(),
+        })
+    }
+}
+
+/// A register write that matched a watchpoint. `pc` is the address of the
+/// writing instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub pc: usize,
+    pub register: u8,
+    pub old: i64,
+    pub new: i64,
+    pub action: Action,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Hit {
+            pc,
+            register,
+            old,
+            new,
+            ..
+        } = self;
+        write!(f, "${register}: {old} -> {new} at {pc:04}")
+    }
+}
+
+pub trait HitSink: fmt::Debug {
+    fn hit(&mut self, hit: &Hit);
+}
+
+impl HitSink for Vec<Hit> {
+    fn hit(&mut self, hit: &Hit) {
+        self.push(*hit);
+    }
+}
+
+/// Writes every hit as a line of text, e.g. `Watch $1: 0 -> 5 at 0000`.
+pub struct TextHits<W: Write> {
+    lines: LineWriter<W>,
+}
+
+impl<W: Write> TextHits<W> {
+    pub fn new(output: W) -> Self {
+        TextHits {
+            lines: LineWriter::new(output, None),
+        }
+    }
+
+    /// Ends every line with the source location of the writing
+    /// instruction, e.g. `Watch $1: 0 -> 5 at 0000 (countdown.rbvm:1)`.
+    pub fn with_debug_info(output: W, debug_info: DebugInfo) -> Self {
+        TextHits {
+            lines: LineWriter::new(output, Some(debug_info)),
+        }
+    }
+
+    /// Returns the number of hits that couldn't be written.
+    pub fn dropped(&self) -> usize {
+        self.lines.dropped()
+    }
+}
+
+impl<W: Write> fmt::Debug for TextHits<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TextHits").finish_non_exhaustive()
+    }
+}
+
+impl<W: Write> HitSink for TextHits<W> {
+    fn hit(&mut self, hit: &Hit) {
+        let line = format_args!("Watch {hit}");
+        self.lines.write_line(line, hit.pc, (" (", ")"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_watchpoint() {
+        let watchpoint: Watchpoint = "$3".parse().unwrap();
+        assert_eq!(watchpoint.register, 3);
+        assert_eq!(watchpoint.condition, Condition::Any);
+        let watchpoint: Watchpoint = "$12 >= -4".parse().unwrap();
+        assert_eq!(watchpoint.register, 12);
+        assert_eq!(watchpoint.condition, Condition::Gte(-4));
+        let watchpoint: Watchpoint = "$0!=7".parse().unwrap();
+        assert_eq!(watchpoint.condition, Condition::Ne(7));
+
+        assert!("3".parse::<Watchpoint>().is_err());
+        assert!("$1 =< 3".parse::<Watchpoint>().is_err());
+        assert!("$1 ==".parse::<Watchpoint>().is_err());
+    }
+
+    #[test]
+    fn test_condition_matches() {
+        assert!(Condition::Any.matches(0));
+        assert!(Condition::Lt(0).matches(-1));
+        assert!(!Condition::Eq(5).matches(4));
+    }
+
+    #[test]
+    fn test_hit_display() {
+        let hit = Hit {
+            pc: 8,
+            register: 2,
+            old: 1,
+            new: -1,
+            action: Action::Log,
+        };
+        assert_eq!(hit.to_string(), "$2: 1 -> -1 at 0008");
+
+        let mut output = vec![];
+        TextHits::new(&mut output).hit(&hit);
+        assert_eq!(output, b"Watch $2: 1 -> -1 at 0008\n");
+    }
+}